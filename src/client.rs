@@ -0,0 +1,986 @@
+use bytes::{Bytes, BytesMut};
+use core::time;
+use log::{debug, info, warn};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use crate::{
+    conformance::{self, Conformance},
+    credentials,
+    packet::{self, Packet, PacketType},
+    qos::QoS,
+    topic::{TopicFilter, TopicName},
+    transport::{self, Connector, Transport},
+};
+
+// 切断する前に、途中のやり取りのACKを待つ時間の上限
+pub(crate) const SHUTDOWN_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+// 再接続に失敗したときに待つ時間 (失敗するたびに倍にする)
+pub(crate) const MIN_BACKOFF: time::Duration = time::Duration::from_secs(1);
+pub(crate) const MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+
+#[derive(Clone)]
+pub(crate) struct ConnectOptions {
+    pub(crate) broker: String,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    // 指定されていれば、passwordの代わりに接続するたびに作ったJWTを使う
+    pub(crate) jwt: Option<credentials::JwtOptions>,
+    pub(crate) client_id: Option<String>,
+    pub(crate) keep_alive: u16,
+    pub(crate) clean_session: bool,
+    pub(crate) will_flag: bool,
+    pub(crate) will_topic: Option<TopicName>,
+    pub(crate) will_message: Option<String>,
+    pub(crate) retry: RetryPolicy,
+    pub(crate) delivery: DeliveryOptions,
+    // 受信したパケットが仕様に沿っていない場合の扱い
+    pub(crate) conformance: Conformance,
+    // 送受信するパケットの最大サイズ (固定ヘッダーを含む)
+    pub(crate) max_packet_size: usize,
+    // TCPで小さいパケットを遅延させずに送る
+    pub(crate) tcp_nodelay: bool,
+    // 再起動後も引き継ぐ状態を保存するディレクトリ
+    pub(crate) state_dir: Option<PathBuf>,
+}
+
+impl fmt::Debug for ConnectOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectOptions")
+            .field("broker", &self.broker)
+            .field("username", &self.username)
+            .field("password", &packet::Redacted(&self.password))
+            .field("jwt", &self.jwt)
+            .field("client_id", &self.client_id)
+            .field("keep_alive", &self.keep_alive)
+            .field("clean_session", &self.clean_session)
+            .field("will_flag", &self.will_flag)
+            .field("will_topic", &self.will_topic)
+            .field("will_message", &packet::Redacted(&self.will_message))
+            .field("retry", &self.retry)
+            .field("delivery", &self.delivery)
+            .field("conformance", &self.conformance)
+            .field("max_packet_size", &self.max_packet_size)
+            .field("tcp_nodelay", &self.tcp_nodelay)
+            .field("state_dir", &self.state_dir)
+            .finish()
+    }
+}
+
+// 受信したQoS1, QoS2のメッセージを渡す方法
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DeliveryOptions {
+    // falseの場合はMethod A (PUBLISHをPUBRELまで保持して、PUBRELを受信したときに渡す)
+    // trueの場合はMethod B (PUBLISHを受信したときに渡し、PUBRELまでパケットIDを覚えておく)
+    // Method BのパケットIDはstate_dirに保存するので、PUBRELの前に再起動しても重複して渡さない
+    pub(crate) qos2_method_b: bool,
+    // DUPフラグの立ったQoS1のPUBLISHが、直近のこの数のパケットIDと重なれば渡さない (0の場合は無効)
+    pub(crate) qos1_dedup_window: usize,
+    // trueの場合はQoS1, QoS2のメッセージもPUBLISHを受信したときに渡し、PUBACK, PUBRECはack()を呼ぶまで送らない
+    // (リレーで転送先に届けてからACKを返すため。QoS2のパケットIDはack()のときにMethod Bと同じく保存する)
+    pub(crate) manual_ack: bool,
+}
+
+// ACKが届かないPUBLISH, PUBRELの再送方法 (再接続時は常に再送する)
+#[derive(Clone, Debug)]
+pub(crate) struct RetryPolicy {
+    // 送信してからこの時間が過ぎても、ACKが届かなければ再送する (Noneの場合は再接続時だけ)
+    pub(crate) ack_timeout: Option<time::Duration>,
+    // 最初の送信を含めた送信回数の上限 (Noneの場合は無制限)
+    pub(crate) max_attempts: Option<u32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            ack_timeout: Some(time::Duration::from_secs(20)),
+            max_attempts: None,
+        }
+    }
+}
+
+// ACK待ちのPUBLISH, PUBRELごとの再送の状態
+struct Retry {
+    // 最初に送信した順番 (再送するときもこの順に送る)
+    sequence: u64,
+    sent_at: Instant,
+    attempts: u32,
+}
+
+pub(crate) struct Client {
+    options: ConnectOptions,
+    connector: Connector,
+    // 接続していない場合はNone
+    transport: Option<Box<dyn Transport>>,
+    // 最後に受信したCONNACKのsession present
+    session_present: bool,
+    read_buffer: BytesMut,
+    // CONNECTを送ってCONNACKを待っている間だけtrue
+    awaiting_connack: bool,
+    // 送信待ちのパケット (flush_writesでまとめて書き込む)
+    write_buffer: BytesMut,
+    pending_writes: Vec<Bytes>,
+    // 受信を待つ最大時間 (Noneの場合は受信するまでブロックする)
+    poll_interval: Option<time::Duration>,
+    // keep aliveのタイマー (最後に送信した時刻)
+    last_sent: Instant,
+    // 応答 (PINGRESPに限らず何かのパケット) を待っているPINGREQを送った時刻
+    pingreq_sent_at: Option<Instant>,
+    // 再送のタイマーと回数 (ACK待ちのPUBLISH, PUBRELごと)
+    retries: HashMap<u16, Retry>,
+    next_sequence: u64,
+    // 送信回数の上限に達して諦めたパケットID (retransmit()で呼び出し元に返す)
+    abandoned: Vec<u16>,
+    unpuback_packets: HashMap<u16, packet::PublishPacket>,
+    unpubrec_packets: HashMap<u16, packet::PublishPacket>,
+    unpubrel_packets: HashMap<u16, packet::PublishPacket>,
+    unpubcomp_packets: HashMap<u16, packet::PubrelPacket>,
+    // Method Bで渡したQoS2のメッセージのうち、PUBRELを受信していないもののパケットID
+    unreleased_packet_ids: HashSet<u16>,
+    // manual_ackで渡したメッセージのうち、ack()を呼んでいないもののパケットID
+    unacked_packet_ids: HashSet<u16>,
+    // 直近に受信したQoS1のメッセージのパケットID
+    recent_qos1_packet_ids: VecDeque<u16>,
+    received_messages: VecDeque<packet::PublishPacket>,
+}
+
+impl Client {
+    pub(crate) fn connect(options: ConnectOptions) -> io::Result<Self> {
+        Self::connect_with(options, Box::new(transport::connect))
+    }
+
+    pub(crate) fn connect_with(options: ConnectOptions, connector: Connector) -> io::Result<Self> {
+        let mut client = Self::new(options, connector);
+        client.reconnect()?;
+
+        Ok(client)
+    }
+
+    // 接続しないままClientを作る (reconnect()で接続する)
+    pub(crate) fn new(options: ConnectOptions, connector: Connector) -> Self {
+        // keep aliveの半分の間隔で受信がなければPINGREQを送る
+        let poll_interval = if options.keep_alive > 0 {
+            Some(time::Duration::from_secs(
+                (options.keep_alive / 2).max(1) as u64
+            ))
+        } else {
+            None
+        };
+        Self {
+            options,
+            connector,
+            transport: None,
+            session_present: false,
+            read_buffer: BytesMut::new(),
+            awaiting_connack: false,
+            write_buffer: BytesMut::new(),
+            pending_writes: vec![],
+            poll_interval,
+            last_sent: Instant::now(),
+            pingreq_sent_at: None,
+            retries: HashMap::new(),
+            next_sequence: 0,
+            abandoned: vec![],
+            unpuback_packets: HashMap::new(),
+            unpubrec_packets: HashMap::new(),
+            unpubrel_packets: HashMap::new(),
+            unpubcomp_packets: HashMap::new(),
+            unreleased_packet_ids: HashSet::new(),
+            unacked_packet_ids: HashSet::new(),
+            recent_qos1_packet_ids: VecDeque::new(),
+            received_messages: VecDeque::new(),
+        }
+    }
+
+    // 同じオプション (生成済みのクライアントIDを含む) で接続し直し、ACKが届いていないPUBLISH, PUBRELを再送する
+    // NOTE: clean sessionの場合もブローカーには新しいメッセージとして届くので、At least onceは保たれる
+    pub(crate) fn reconnect(&mut self) -> io::Result<()> {
+        self.close();
+        self.transport = Some((self.connector)(&self.options.broker)?);
+        if let Err(e) = self.open() {
+            self.close();
+            return Err(e);
+        }
+
+        let mut packet_ids: Vec<u16> = self.retries.keys().copied().collect();
+        packet_ids.sort_by_key(|packet_id| self.retries[packet_id].sequence);
+        for packet_id in packet_ids {
+            self.resend(packet_id);
+        }
+        self.flush_writes()
+    }
+
+    // 受信を待つ最大時間を変える (イベントループで送信要求やタイマーを処理する間隔)
+    pub(crate) fn set_poll_interval(
+        &mut self,
+        poll_interval: Option<time::Duration>,
+    ) -> io::Result<()> {
+        self.poll_interval = poll_interval;
+        match &self.transport {
+            Some(transport) => transport.set_read_timeout(poll_interval),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.transport.is_some()
+    }
+
+    // 接続時にブローカーがセッションを保持していたか (falseの場合は購読し直す必要がある)
+    pub(crate) fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    // DISCONNECTを送らずに接続を閉じる (ACK待ちの状態は再接続に備えて残す)
+    pub(crate) fn close(&mut self) {
+        if let Some(transport) = self.transport.take() {
+            let _ = transport.shutdown();
+        }
+        self.read_buffer.clear();
+        // 書きかけのパケットを次の接続で送らないようにする
+        self.write_buffer.clear();
+        self.pending_writes.clear();
+        self.pingreq_sent_at = None;
+    }
+
+    // ACKを待たずにPUBLISHを送る (QoS1, 2はACKを受信するまで保持する)
+    // 送信に失敗しても保持したままにして、再接続時に再送する
+    pub(crate) fn send_publish(&mut self, publish_packet: packet::PublishPacket) -> io::Result<()> {
+        self.queue_publish(publish_packet)?;
+        self.flush_writes()
+    }
+
+    // send_publishと同じだが、flush_writesを呼ぶまで書き込まない
+    pub(crate) fn queue_publish(
+        &mut self,
+        publish_packet: packet::PublishPacket,
+    ) -> io::Result<()> {
+        debug!("Send publish_packet={:?}", publish_packet);
+        self.check_packet_size(&publish_packet)?;
+
+        if let Some(packet_id) = publish_packet.packet_id {
+            self.retries.insert(
+                packet_id,
+                Retry {
+                    sequence: self.next_sequence,
+                    sent_at: Instant::now(),
+                    attempts: 1,
+                },
+            );
+            self.next_sequence += 1;
+            match publish_packet.qos {
+                QoS::QoS0 => { /* NOP */ }
+                QoS::QoS1 => {
+                    self.unpuback_packets
+                        .insert(packet_id, publish_packet.clone());
+                }
+                QoS::QoS2 => {
+                    self.unpubrec_packets
+                        .insert(packet_id, publish_packet.clone());
+                }
+            }
+        }
+
+        self.queue_publish_packet(&publish_packet);
+        Ok(())
+    }
+
+    // 大きすぎるメッセージは送らずにエラーにする (再送の対象にもしない)
+    pub(crate) fn check_packet_size(
+        &self,
+        publish_packet: &packet::PublishPacket,
+    ) -> io::Result<()> {
+        let size = publish_packet.encoded_len();
+        if size > self.options.max_packet_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Message is too large. size={}, max_packet_size={}",
+                    size, self.options.max_packet_size
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn publish(&mut self, publish_packet: packet::PublishPacket) -> io::Result<()> {
+        let packet_id = publish_packet.packet_id;
+        self.send_publish(publish_packet)?;
+        self.wait_for_ack(packet_id)
+    }
+
+    // PUBACKまたはPUBCOMPを受信するまで、受信と再送を続ける
+    pub(crate) fn wait_for_ack(&mut self, packet_id: Option<u16>) -> io::Result<()> {
+        let Some(packet_id) = packet_id else {
+            return Ok(());
+        };
+        while self.is_in_flight(packet_id) {
+            if self.retransmit()?.contains(&packet_id) {
+                return Err(gave_up_error(packet_id));
+            }
+            self.receive()?;
+        }
+        Ok(())
+    }
+
+    // PUBACKまたはPUBCOMPを待っているか
+    pub(crate) fn is_in_flight(&self, packet_id: u16) -> bool {
+        self.unpuback_packets.contains_key(&packet_id)
+            || self.unpubrec_packets.contains_key(&packet_id)
+            || self.unpubcomp_packets.contains_key(&packet_id)
+    }
+
+    // 途中で止まっているQoS1, QoS2のやり取りがあるか (受信側のPUBREL待ちも含む)
+    pub(crate) fn has_in_flight(&self) -> bool {
+        !self.unpuback_packets.is_empty()
+            || !self.unpubrec_packets.is_empty()
+            || !self.unpubrel_packets.is_empty()
+            || !self.unpubcomp_packets.is_empty()
+            || !self.unreleased_packet_ids.is_empty()
+    }
+
+    pub(crate) fn send_subscribe(
+        &mut self,
+        topic_filters: Vec<(TopicFilter, QoS)>,
+    ) -> io::Result<u16> {
+        let subscribe_packet = packet::SubscribePacket {
+            packet_id: packet::generate_packet_id(),
+            topic_filters,
+        };
+        debug!("Send subscribe_packet={:?}", subscribe_packet);
+        self.send(&subscribe_packet)?;
+
+        Ok(subscribe_packet.packet_id)
+    }
+
+    // トピックフィルターごとに許可されたQoSを返す
+    pub(crate) fn subscribe(
+        &mut self,
+        topic_filters: Vec<(TopicFilter, QoS)>,
+    ) -> io::Result<Vec<(TopicFilter, QoS)>> {
+        let packet_id = self.send_subscribe(topic_filters.clone())?;
+
+        loop {
+            if let Some(PacketType::SUBACK(suback_packet)) = self.receive()? {
+                return check_suback(packet_id, &topic_filters, suback_packet);
+            }
+        }
+    }
+
+    pub(crate) fn send_unsubscribe(&mut self, topic_filters: Vec<TopicFilter>) -> io::Result<u16> {
+        let unsubscribe_packet = packet::UnsubscribePacket {
+            packet_id: packet::generate_packet_id(),
+            topic_filters,
+        };
+        debug!("Send unsubscribe_packet={:?}", unsubscribe_packet);
+        self.send(&unsubscribe_packet)?;
+
+        Ok(unsubscribe_packet.packet_id)
+    }
+
+    // 配送すべきメッセージがあれば返す
+    // keep aliveの間隔内に何も受信しなければPINGREQを送ってNoneを返す
+    pub(crate) fn poll(&mut self) -> io::Result<Option<packet::PublishPacket>> {
+        if self.received_messages.is_empty() {
+            self.receive()?;
+        }
+        Ok(self.take_message())
+    }
+
+    pub(crate) fn take_message(&mut self) -> Option<packet::PublishPacket> {
+        self.received_messages.pop_front()
+    }
+
+    // manual_ackのときに、渡したメッセージのPUBACK (QoS1), PUBREC (QoS2)を送る
+    pub(crate) fn ack(&mut self, publish_packet: &packet::PublishPacket) -> io::Result<()> {
+        let Some(packet_id) = publish_packet.packet_id else {
+            return Ok(());
+        };
+        self.unacked_packet_ids.remove(&packet_id);
+        match publish_packet.qos {
+            QoS::QoS0 => return Ok(()),
+            QoS::QoS1 => self.queue(&packet::PubackPacket { packet_id }),
+            QoS::QoS2 => {
+                // PUBRECを送る前に保存しておく (Method B pattern)
+                if self.unreleased_packet_ids.insert(packet_id) {
+                    self.save_unreleased_packet_ids();
+                }
+                self.queue(&packet::PubrecPacket { packet_id });
+            }
+        }
+        self.flush_writes()
+    }
+
+    // 最後の送信からkeep aliveの半分が経過していればPINGREQを送る
+    // PINGREQを送ってからkeep aliveの間に何も受信しなければ、接続が切れたものとして閉じる
+    pub(crate) fn ping_if_idle(&mut self) -> io::Result<()> {
+        if self.options.keep_alive == 0 {
+            return Ok(());
+        }
+        let keep_alive = time::Duration::from_secs(self.options.keep_alive as u64);
+        if let Some(sent_at) = self.pingreq_sent_at {
+            if sent_at.elapsed() >= keep_alive {
+                self.close();
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "No PINGRESP from broker within keep alive",
+                ));
+            }
+        }
+        if self.last_sent.elapsed() >= keep_alive / 2 {
+            debug!("Send pingreq_packet");
+            self.send(&packet::PingreqPacket {})?;
+            self.pingreq_sent_at.get_or_insert_with(Instant::now);
+        }
+        Ok(())
+    }
+
+    // ack_timeoutを過ぎてもACKが届かないPUBLISH (DUP=1), PUBRELを再送し、送信回数の上限に達して諦めたパケットIDを返す
+    pub(crate) fn retransmit(&mut self) -> io::Result<Vec<u16>> {
+        if let Some(ack_timeout) = self.options.retry.ack_timeout {
+            let mut expired: Vec<(u64, u16)> = self
+                .retries
+                .iter()
+                .filter(|(_, retry)| retry.sent_at.elapsed() >= ack_timeout)
+                .map(|(packet_id, retry)| (retry.sequence, *packet_id))
+                .collect();
+            expired.sort();
+
+            for (_, packet_id) in expired {
+                self.resend(packet_id);
+            }
+            self.flush_writes()?;
+        }
+
+        Ok(std::mem::take(&mut self.abandoned))
+    }
+
+    fn resend(&mut self, packet_id: u16) {
+        let Some(retry) = self.retries.get_mut(&packet_id) else {
+            return;
+        };
+        if let Some(max_attempts) = self.options.retry.max_attempts {
+            if retry.attempts >= max_attempts {
+                warn!(
+                    "Give up retransmitting. packet_id={}, attempts={}",
+                    packet_id, retry.attempts
+                );
+                self.retries.remove(&packet_id);
+                self.unpuback_packets.remove(&packet_id);
+                self.unpubrec_packets.remove(&packet_id);
+                self.unpubcomp_packets.remove(&packet_id);
+                self.abandoned.push(packet_id);
+                return;
+            }
+        }
+        retry.attempts += 1;
+        retry.sent_at = Instant::now();
+
+        if let Some(publish_packet) = self
+            .unpuback_packets
+            .get_mut(&packet_id)
+            .or(self.unpubrec_packets.get_mut(&packet_id))
+        {
+            publish_packet.dup = true;
+            let publish_packet = publish_packet.clone();
+            warn!("Retransmit publish_packet={:?}", publish_packet);
+            self.queue_publish_packet(&publish_packet);
+        } else if let Some(pubrel_packet) = self.unpubcomp_packets.get(&packet_id).cloned() {
+            warn!("Retransmit pubrel_packet={:?}", pubrel_packet);
+            self.queue(&pubrel_packet);
+        } else {
+            self.retries.remove(&packet_id);
+        }
+    }
+
+    pub(crate) fn disconnect(&mut self) -> io::Result<()> {
+        let disconnect_packet = packet::DisconnectPacket {};
+        debug!("Send disconnect_packet={:?}", disconnect_packet);
+        self.send(&disconnect_packet)?;
+        // DISCONNECTを送った後はブローカーから何も届かないので、こちらから接続を閉じる
+        // (ブローカーが先に閉じていればエラーになるが、どちらでも切断は済んでいる)
+        self.close();
+        Ok(())
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let poll_interval = self.poll_interval;
+        self.transport()?.set_read_timeout(poll_interval)?;
+        let tcp_nodelay = self.options.tcp_nodelay;
+        self.transport()?.set_nodelay(tcp_nodelay)?;
+
+        let password = match &self.options.jwt {
+            Some(jwt) => Some(credentials::generate_jwt(jwt)?),
+            None => self.options.password.clone(),
+        };
+        let connect_packet = packet::ConnectPacket::new(
+            self.options.username.clone(),
+            password,
+            self.options.client_id.clone(),
+            self.options.keep_alive,
+            self.options.clean_session,
+            self.options.will_flag,
+            self.options.will_topic.clone(),
+            self.options.will_message.clone(),
+        )?;
+        // 再接続時に同じクライアントIDを使うため、生成したIDを保持しておく
+        self.options.client_id = Some(connect_packet.client_id.clone());
+
+        debug!("Send connect_packet={:?}", connect_packet);
+        self.send(&connect_packet)?;
+
+        self.awaiting_connack = true;
+        let result = self.read_packet();
+        self.awaiting_connack = false;
+        let bytes = match result? {
+            Some(bytes) => bytes,
+            None => return Err(io::Error::new(io::ErrorKind::TimedOut, "CONNACK timed out")),
+        };
+        if bytes[0] >> 4 != 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Protocol violation. reason=Expected CONNACK. packet_type={}",
+                    bytes[0] >> 4
+                ),
+            ));
+        }
+        let (connack_packet, _) = packet::ConnackPacket::deserialize(&bytes);
+        debug!("Received connack_packet={:?}", connack_packet);
+
+        if !connack_packet.accepted {
+            return Err(io::Error::other(format!(
+                "Connection refused. reason={}",
+                connack_packet.refused_reason.unwrap_or_default()
+            )));
+        }
+        info!(
+            "Connected to broker={}, session_present={}",
+            self.options.broker, connack_packet.sp
+        );
+        self.session_present = connack_packet.sp;
+
+        // ACKを返していないメッセージはブローカーが再送してくるので、もう一度渡す
+        self.unacked_packet_ids.clear();
+        // セッションが引き継がれなければ、ブローカーはPUBRELを送ってこない
+        if self.delivers_qos2_on_publish() {
+            if connack_packet.sp {
+                self.load_unreleased_packet_ids();
+            } else {
+                self.unreleased_packet_ids.clear();
+                self.save_unreleased_packet_ids();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn transport(&mut self) -> io::Result<&mut Box<dyn Transport>> {
+        self.transport.as_mut().ok_or_else(not_connected)
+    }
+
+    // 送信待ちのパケットと一緒にすぐ書き込む
+    fn send<P: Packet>(&mut self, packet: &P) -> io::Result<()> {
+        self.queue(packet);
+        self.flush_writes()
+    }
+
+    // 続けて送る小さいパケット (PUBACKなど) は、送信用のバッファに続けて書き込んでおく
+    fn queue<P: Packet>(&mut self, packet: &P) {
+        packet.encode(&mut self.write_buffer);
+    }
+
+    // PUBLISHのペイロードは送信用のバッファにコピーせずに、そのまま書き込む
+    fn queue_publish_packet(&mut self, publish_packet: &packet::PublishPacket) {
+        publish_packet.encode_header(&mut self.write_buffer);
+        self.pending_writes.push(self.write_buffer.split().freeze());
+        if !publish_packet.payload.is_empty() {
+            self.pending_writes.push(publish_packet.payload.clone());
+        }
+    }
+
+    // 送信待ちのパケットを1回の書き込み (writev) で送る
+    pub(crate) fn flush_writes(&mut self) -> io::Result<()> {
+        if !self.write_buffer.is_empty() {
+            self.pending_writes.push(self.write_buffer.split().freeze());
+        }
+        if self.pending_writes.is_empty() {
+            return Ok(());
+        }
+        let segments = std::mem::take(&mut self.pending_writes);
+
+        let transport = self.transport.as_mut().ok_or_else(not_connected)?;
+        transport::write_all_vectored(transport.as_mut(), &segments)?;
+        transport.flush()?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    // 1パケット分のバイト列を返す (タイムアウトした場合はNone)
+    // 読み出したバイト列は、パケットの区切りに関係なくread_bufferに溜めておく
+    // 返すバイト列はread_bufferから切り離したもので、PUBLISHのトピック名とペイロードはこれを共有する
+    fn read_packet(&mut self) -> io::Result<Option<Bytes>> {
+        loop {
+            if let Some(length) = packet::declared_packet_length(&self.read_buffer)? {
+                // 本体を受信する前に切断して、大きなパケットをバッファに溜めないようにする
+                if length > self.options.max_packet_size {
+                    self.close();
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Received packet is too large. size={}, max_packet_size={}",
+                            length, self.options.max_packet_size
+                        ),
+                    ));
+                }
+            }
+            if let Some(length) = packet::complete_packet_length(&self.read_buffer)? {
+                let bytes = self.read_buffer.split_to(length).freeze();
+                self.pingreq_sent_at = None;
+                self.check_conformance(&bytes)?;
+                return Ok(Some(bytes));
+            }
+
+            // 受信を待つ前に、溜めておいた返信を送る
+            self.flush_writes()?;
+
+            let mut buffer = [0; 4096];
+            match self.transport()?.read(&mut buffer) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(size) => self.read_buffer.extend_from_slice(&buffer[..size]),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Strictでは違反があれば切断する
+    // Lenientではパケットを解釈できない違反だけ切断し、それ以外は警告を出して処理を続ける
+    fn check_conformance(&self, bytes: &[u8]) -> io::Result<()> {
+        let mut violations = conformance::check(bytes);
+        // CONNACKは接続時 (open) にしか届かない
+        if bytes[0] >> 4 == 2 && !self.awaiting_connack {
+            violations.push(conformance::Violation {
+                reason: "Unexpected CONNACK after connected".to_string(),
+                fatal: true,
+            });
+        }
+        for violation in violations {
+            if violation.fatal || self.options.conformance == Conformance::Strict {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Protocol violation. reason={}", violation.reason),
+                ));
+            }
+            warn!("Protocol violation. reason={}", violation.reason);
+        }
+        Ok(())
+    }
+
+    // 同じパケットIDでも、DUPフラグがなければブローカーが新しいメッセージに使い回したもの
+    fn is_duplicate_qos1(&mut self, publish_packet: &packet::PublishPacket) -> bool {
+        let window = self.options.delivery.qos1_dedup_window;
+        if window == 0 {
+            return false;
+        }
+        let packet_id = publish_packet.packet_id.unwrap();
+        if publish_packet.dup && self.recent_qos1_packet_ids.contains(&packet_id) {
+            return true;
+        }
+
+        self.recent_qos1_packet_ids.retain(|id| *id != packet_id);
+        self.recent_qos1_packet_ids.push_back(packet_id);
+        if self.recent_qos1_packet_ids.len() > window {
+            self.recent_qos1_packet_ids.pop_front();
+        }
+        false
+    }
+
+    // QoS2のメッセージをPUBLISHを受信したときに渡すか (Method B, manual_ack) PUBRELまで保持するか (Method A)
+    fn delivers_qos2_on_publish(&self) -> bool {
+        self.options.delivery.qos2_method_b || self.options.delivery.manual_ack
+    }
+
+    // Method BのパケットIDを保存するファイル (クライアントIDごと)
+    fn unreleased_packet_ids_path(&self) -> Option<PathBuf> {
+        let state_dir = self.options.state_dir.as_ref()?;
+        let client_id = self.options.client_id.as_ref()?;
+        Some(state_dir.join(format!("{}.qos2", sanitize_file_name(client_id))))
+    }
+
+    fn load_unreleased_packet_ids(&mut self) {
+        let Some(path) = self.unreleased_packet_ids_path() else {
+            return;
+        };
+        let Ok(contents) = fs::read_to_string(&path) else {
+            return;
+        };
+        self.unreleased_packet_ids
+            .extend(contents.lines().filter_map(|line| line.parse::<u16>().ok()));
+        debug!(
+            "Loaded unreleased_packet_ids={:?}",
+            self.unreleased_packet_ids
+        );
+    }
+
+    fn save_unreleased_packet_ids(&self) {
+        let Some(path) = self.unreleased_packet_ids_path() else {
+            return;
+        };
+        let contents: String = self
+            .unreleased_packet_ids
+            .iter()
+            .map(|packet_id| format!("{}\n", packet_id))
+            .collect();
+        if let Err(e) = write_file_atomically(&path, contents) {
+            warn!(
+                "Failed to save unreleased packet IDs. path={}, error={}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    // 1パケット受信して、QoSの状態を更新し、必要な返信を送る
+    // poll_intervalの間に何も受信しなければNoneを返す
+    pub(crate) fn receive(&mut self) -> io::Result<Option<PacketType>> {
+        // keep aliveは最後の送信から数えるので、受信が続いていても毎回確かめる
+        self.ping_if_idle()?;
+        let Some(bytes) = self.read_packet()? else {
+            return Ok(None);
+        };
+        let (received_packet, mut replied_packet, _) =
+            packet::create_replay_packet_with_received_packet(&bytes, 0)?;
+        debug!("Received packet={:?}", received_packet);
+
+        match &received_packet {
+            PacketType::PUBLISH(publish_packet) => match publish_packet.qos {
+                QoS::QoS0 => self.received_messages.push_back(publish_packet.clone()),
+                QoS::QoS1 => {
+                    let packet_id = publish_packet.packet_id.unwrap();
+                    if self.unacked_packet_ids.contains(&packet_id)
+                        || self.is_duplicate_qos1(publish_packet)
+                    {
+                        debug!("Duplicate publish_packet={:?}", publish_packet);
+                    } else {
+                        if self.options.delivery.manual_ack {
+                            self.unacked_packet_ids.insert(packet_id);
+                        }
+                        self.received_messages.push_back(publish_packet.clone());
+                    }
+                }
+                QoS::QoS2 if self.options.delivery.manual_ack => {
+                    let packet_id = publish_packet.packet_id.unwrap();
+                    if self.unacked_packet_ids.contains(&packet_id)
+                        || self.unreleased_packet_ids.contains(&packet_id)
+                    {
+                        debug!("Duplicate publish_packet={:?}", publish_packet);
+                    } else {
+                        self.unacked_packet_ids.insert(packet_id);
+                        self.received_messages.push_back(publish_packet.clone());
+                    }
+                }
+                QoS::QoS2 if self.options.delivery.qos2_method_b => {
+                    // 渡したことをPUBRECを送る前に保存しておく (Method B pattern)
+                    if self
+                        .unreleased_packet_ids
+                        .insert(publish_packet.packet_id.unwrap())
+                    {
+                        self.save_unreleased_packet_ids();
+                        self.received_messages.push_back(publish_packet.clone());
+                    } else {
+                        debug!("Duplicate publish_packet={:?}", publish_packet);
+                    }
+                }
+                QoS::QoS2 => {
+                    self.unpubrel_packets
+                        .insert(publish_packet.packet_id.unwrap(), publish_packet.clone());
+                }
+            },
+            PacketType::PUBACK(puback_packet) => {
+                self.unpuback_packets.remove(&puback_packet.packet_id);
+                self.retries.remove(&puback_packet.packet_id);
+            }
+            PacketType::PUBREC(pubrec_packet) => {
+                self.unpubrec_packets.remove(&pubrec_packet.packet_id);
+                // PUBRELの送信回数は数え直す
+                if let Some(retry) = self.retries.get_mut(&pubrec_packet.packet_id) {
+                    retry.sent_at = Instant::now();
+                    retry.attempts = 1;
+                }
+                self.unpubcomp_packets.insert(
+                    pubrec_packet.packet_id,
+                    packet::PubrelPacket {
+                        packet_id: pubrec_packet.packet_id,
+                    },
+                );
+            }
+            // 覚えていないパケットIDの場合は、Method Aと同じく警告だけ出す
+            PacketType::PUBREL(pubrel_packet)
+                if self.delivers_qos2_on_publish()
+                    && self.unreleased_packet_ids.remove(&pubrel_packet.packet_id) =>
+            {
+                self.save_unreleased_packet_ids();
+            }
+            PacketType::PUBREL(pubrel_packet) => {
+                // PUBREL受信時に保持しておいたメッセージを削除する (Method A pattern)
+                match self.unpubrel_packets.remove(&pubrel_packet.packet_id) {
+                    Some(publish_packet) => self.received_messages.push_back(publish_packet),
+                    // PUBREL受信時にはブローカーからは削除されているので、再送処理できない?
+                    None => warn!(
+                        "Unstored publish packet. packet_id={}",
+                        pubrel_packet.packet_id
+                    ),
+                }
+            }
+            PacketType::PUBCOMP(pubcomp_packet) => {
+                self.unpubcomp_packets.remove(&pubcomp_packet.packet_id);
+                self.retries.remove(&pubcomp_packet.packet_id);
+            }
+            PacketType::UNSUBACK(unsuback_packet) => {
+                info!("Unsubscribed. packet_id={}", unsuback_packet.packet_id);
+            }
+            _ => {}
+        }
+
+        // ack()を呼ぶまでPUBACK, PUBRECは送らない (重複して届いた場合も含む)
+        if let PacketType::PUBLISH(packet::PublishPacket {
+            packet_id: Some(packet_id),
+            ..
+        }) = &received_packet
+        {
+            if self.unacked_packet_ids.contains(packet_id) {
+                replied_packet = None;
+            }
+        }
+
+        // 次のパケットがすでに届いていれば、返信は溜めておいてまとめて送る (連続したPUBACKなど)
+        if let Some(replied_packet) = replied_packet {
+            debug!("Send packet={:?}", replied_packet);
+            self.queue(&replied_packet);
+        }
+        if packet::complete_packet_length(&self.read_buffer)?.is_none() {
+            self.flush_writes()?;
+        }
+
+        Ok(Some(received_packet))
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "Not connected to broker")
+}
+
+pub(crate) fn gave_up_error(packet_id: u16) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!(
+            "No acknowledgement after retransmitting. packet_id={}",
+            packet_id
+        ),
+    )
+}
+
+// クライアントIDを指定しなかった場合に使うID
+// 永続セッションを引き継げるように、keyごとに一度だけ作ってstate_dirに保存しておく
+pub(crate) fn stored_client_id(state_dir: &Path, key: &str) -> io::Result<String> {
+    let path = state_dir.join(format!("{}.clientid", sanitize_file_name(key)));
+    match fs::read_to_string(&path) {
+        Ok(contents) if !contents.trim().is_empty() => return Ok(contents.trim().to_string()),
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let client_id = packet::generate_client_id();
+    write_file_atomically(&path, format!("{}\n", client_id))?;
+    info!("Generated client_id={}, path={}", client_id, path.display());
+    Ok(client_id)
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+// 書き込みの途中で止まっても壊れないように、一時ファイルに書いてから置き換える
+fn write_file_atomically(path: &Path, contents: String) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    path.parent().map_or(Ok(()), fs::create_dir_all)?;
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)
+}
+
+// SUBACKのリターンコードをSUBSCRIBEのトピックフィルターと対応づけて、許可されたQoSを返す
+// 1つでも失敗 (0x80) があればエラーにする
+pub(crate) fn check_suback(
+    packet_id: u16,
+    topic_filters: &[(TopicFilter, QoS)],
+    suback_packet: packet::SubackPacket,
+) -> io::Result<Vec<(TopicFilter, QoS)>> {
+    if packet_id != suback_packet.packet_id {
+        return Err(io::Error::other(format!(
+            "SUBACK Packet ID is not matched. packet_id={}, suback_packet={:?}",
+            packet_id, suback_packet
+        )));
+    }
+    if topic_filters.len() != suback_packet.return_codes.len() {
+        return Err(io::Error::other(format!(
+            "SUBACK return codes are not matched with topic filters. topic_filters={:?}, suback_packet={:?}",
+            topic_filters, suback_packet
+        )));
+    }
+
+    let mut granted = vec![];
+    for ((topic_filter, _), return_code) in topic_filters.iter().zip(suback_packet.return_codes) {
+        match return_code {
+            Some(qos) => granted.push((topic_filter.clone(), qos)),
+            None => {
+                return Err(io::Error::other(format!(
+                    "Subscription is rejected. topic_filter={}",
+                    topic_filter
+                )))
+            }
+        }
+    }
+    Ok(granted)
+}
+
+// 要求したQoSより低いQoSが許可された場合に、require_qosであればエラー、そうでなければ警告にする
+pub(crate) fn check_granted_qos(
+    topic_filters: &[(TopicFilter, QoS)],
+    granted: &[(TopicFilter, QoS)],
+    require_qos: bool,
+) -> io::Result<()> {
+    for ((topic_filter, requested_qos), (_, granted_qos)) in topic_filters.iter().zip(granted) {
+        if granted_qos >= requested_qos {
+            continue;
+        }
+        if require_qos {
+            return Err(io::Error::other(format!(
+                "QoS is downgraded. topic_filter={}, requested_qos={:?}, granted_qos={:?}",
+                topic_filter, requested_qos, granted_qos
+            )));
+        }
+        warn!(
+            "QoS is downgraded. topic_filter={}, requested_qos={:?}, granted_qos={:?}",
+            topic_filter, requested_qos, granted_qos
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,110 @@
+// 設定ファイル (TOML) の名前付きプロファイル
+//
+// [profile.prod-eu]
+// broker = "mqtt.eu.example.com:1883"
+// username = "alice"
+// qos = 1
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    profile: HashMap<String, Profile>,
+}
+
+// 値がないものはコマンドラインの既定値を使う
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Profile {
+    pub(crate) broker: Option<String>,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    pub(crate) password_file: Option<PathBuf>,
+    pub(crate) clientid: Option<String>,
+    pub(crate) qos: Option<u8>,
+    pub(crate) keepalive: Option<u16>,
+    pub(crate) cleansession: Option<bool>,
+    // 通信路がTLSに対応するまでは、指定されていれば接続せずにエラーにする
+    pub(crate) tls: Option<toml::Table>,
+}
+
+// $XDG_CONFIG_HOME/rust-mqtt/config.toml (未設定なら ~/.config/rust-mqtt/config.toml)
+fn default_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("rust-mqtt").join("config.toml"))
+}
+
+// pathがNoneの場合は既定の場所を読む
+// 既定の場所にファイルがなく、プロファイルも指定されていなければ空のプロファイルを返す
+pub(crate) fn load_profile(path: Option<&Path>, name: Option<&str>) -> io::Result<Profile> {
+    let (path, explicit) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => return not_found_unless_default(name),
+        },
+    };
+
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !explicit => {
+            return not_found_unless_default(name)
+        }
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "Failed to read config. path={}, error={}",
+                    path.display(),
+                    e
+                ),
+            ))
+        }
+    };
+    let mut config: Config = toml::from_str(&text).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid config. path={}, error={}", path.display(), e),
+        )
+    })?;
+
+    let Some(name) = name else {
+        return Ok(Profile::default());
+    };
+    let profile = config.profile.remove(name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "Profile is not found. path={}, profile={}",
+                path.display(),
+                name
+            ),
+        )
+    })?;
+    if profile.qos.is_some_and(|qos| qos > 2) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid qos in profile. profile={}", name),
+        ));
+    }
+    Ok(profile)
+}
+
+fn not_found_unless_default(name: Option<&str>) -> io::Result<Profile> {
+    match name {
+        Some(name) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Config file is not found. profile={}", name),
+        )),
+        None => Ok(Profile::default()),
+    }
+}
@@ -0,0 +1,212 @@
+// ブローカーから受信したパケットがMQTT 3.1.1に沿っているかを確かめる
+// 不具合のあるブローカーを調べられるように、違反を切断せずにログに出すだけのモードも用意する
+use crate::{packet, topic};
+
+// 固定ヘッダーの上位4ビット
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBACK: u8 = 9;
+const UNSUBACK: u8 = 11;
+const PINGRESP: u8 = 13;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Conformance {
+    // 違反があれば切断する (仕様どおり)
+    #[default]
+    Strict,
+    // 読み進められる違反は警告を出すだけにする
+    Lenient,
+}
+
+pub(crate) fn parse_conformance(mode: &str) -> Result<Conformance, String> {
+    match mode {
+        "strict" => Ok(Conformance::Strict),
+        "lenient" => Ok(Conformance::Lenient),
+        _ => Err(format!(
+            "Invalid conformance mode: '{}'. (strict, lenient)",
+            mode
+        )),
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Violation {
+    pub(crate) reason: String,
+    // パケットを解釈できない違反 (Lenientでも切断する)
+    pub(crate) fatal: bool,
+}
+
+// bytesは1パケット分 (complete_packet_lengthで切り出したもの)
+pub(crate) fn check(bytes: &[u8]) -> Vec<Violation> {
+    let mut violations = vec![];
+    let packet_type = bytes[0] >> 4;
+    let flags = bytes[0] & 0b0000_1111;
+    let (_, i) = packet::extract_remaining_length(bytes);
+    let body = &bytes[i..];
+
+    let (expected_flags, length) = match packet_type {
+        PUBLISH => {
+            check_publish(flags, body, &mut violations);
+            return violations;
+        }
+        CONNACK | PUBACK | PUBREC | PUBCOMP | UNSUBACK => (0, 2),
+        PUBREL => (0b0010, 2),
+        // SUBACKはパケットIDとリターンコード1つ以上
+        SUBACK => (0, 3),
+        PINGRESP => (0, 0),
+        _ => {
+            fatal(
+                &mut violations,
+                format!(
+                    "Unexpected packet type from server. packet_type={}",
+                    packet_type
+                ),
+            );
+            return violations;
+        }
+    };
+
+    if flags != expected_flags {
+        minor(
+            &mut violations,
+            format!(
+                "Reserved flags are not {:04b}. packet_type={}, flags={:04b}",
+                expected_flags, packet_type, flags
+            ),
+        );
+    }
+    if body.len() < length {
+        fatal(
+            &mut violations,
+            format!(
+                "Remaining length is too short. packet_type={}, remaining_length={}",
+                packet_type,
+                body.len()
+            ),
+        );
+        return violations;
+    }
+    if body.len() > length && packet_type != SUBACK {
+        minor(
+            &mut violations,
+            format!(
+                "Trailing bytes. packet_type={}, remaining_length={}, expected={}",
+                packet_type,
+                body.len(),
+                length
+            ),
+        );
+    }
+
+    match packet_type {
+        CONNACK => {
+            if body[0] & 0b1111_1110 != 0 {
+                minor(
+                    &mut violations,
+                    format!("Reserved connack flags are set. flags={:08b}", body[0]),
+                );
+            }
+            if body[1] > 5 {
+                minor(
+                    &mut violations,
+                    format!("Unknown connack return code. return_code={}", body[1]),
+                );
+            }
+            if body[1] != 0 && body[0] & 1 == 1 {
+                minor(
+                    &mut violations,
+                    "Session present is set on refused connection".to_string(),
+                );
+            }
+        }
+        PINGRESP => {}
+        _ => check_packet_id(packet_type, body, &mut violations),
+    }
+    if packet_type == SUBACK {
+        for code in &body[2..] {
+            if !matches!(code, 0..=2 | 0x80) {
+                minor(
+                    &mut violations,
+                    format!("Unknown suback return code. return_code={:#04x}", code),
+                );
+            }
+        }
+    }
+
+    violations
+}
+
+fn check_publish(flags: u8, body: &[u8], violations: &mut Vec<Violation>) {
+    let qos = (flags & 0b0110) >> 1;
+    if qos == 3 {
+        fatal(violations, "QoS 3 is not allowed".to_string());
+        return;
+    }
+    if qos == 0 && flags & 0b1000 != 0 {
+        minor(violations, "DUP must be 0 for QoS 0".to_string());
+    }
+
+    if body.len() < 2 {
+        fatal(
+            violations,
+            "Remaining length is too short. packet_type=3".to_string(),
+        );
+        return;
+    }
+    let topic_length = u16::from_be_bytes([body[0], body[1]]) as usize;
+    let packet_id_length = if qos > 0 { 2 } else { 0 };
+    if body.len() < 2 + topic_length + packet_id_length {
+        fatal(
+            violations,
+            format!(
+                "Remaining length is too short. packet_type=3, remaining_length={}, topic_length={}",
+                body.len(),
+                topic_length
+            ),
+        );
+        return;
+    }
+
+    let topic = match std::str::from_utf8(&body[2..2 + topic_length]) {
+        Ok(topic) => topic,
+        Err(e) => {
+            fatal(violations, format!("Topic name is not UTF-8. error={}", e));
+            return;
+        }
+    };
+    if let Err(e) = topic::check_name(topic) {
+        fatal(violations, e.to_string());
+        return;
+    }
+
+    if qos > 0 {
+        check_packet_id(PUBLISH, &body[2 + topic_length..], violations);
+    }
+}
+
+fn check_packet_id(packet_type: u8, bytes: &[u8], violations: &mut Vec<Violation>) {
+    if bytes[0] == 0 && bytes[1] == 0 {
+        minor(
+            violations,
+            format!("Packet ID must not be 0. packet_type={}", packet_type),
+        );
+    }
+}
+
+fn fatal(violations: &mut Vec<Violation>, reason: String) {
+    violations.push(Violation {
+        reason,
+        fatal: true,
+    });
+}
+
+fn minor(violations: &mut Vec<Violation>, reason: String) {
+    violations.push(Violation {
+        reason,
+        fatal: false,
+    });
+}
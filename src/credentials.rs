@@ -0,0 +1,127 @@
+// パスワードの取得元
+// コマンドライン (-p) はシェルの履歴やpsに残るので、ファイル・環境変数・プロンプトからも読めるようにする
+use core::time;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use log::warn;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+// 末尾の改行は取り除く (echo "pass" > FILE で作ったファイルをそのまま使えるように)
+pub(crate) fn read_password_file(path: &Path) -> io::Result<String> {
+    let text = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "Failed to read password file. path={}, error={}",
+                path.display(),
+                e
+            ),
+        )
+    })?;
+    warn_if_readable_by_others(path);
+    Ok(text.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(unix)]
+fn warn_if_readable_by_others(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        if metadata.permissions().mode() & 0o077 != 0 {
+            warn!(
+                "Password file is accessible by other users. path={}",
+                path.display()
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_readable_by_others(_path: &Path) {}
+
+// 入力を画面に表示せずに読む
+pub(crate) fn prompt_password(username: &str) -> io::Result<String> {
+    rpassword::prompt_password(format!("Password for {}: ", username))
+}
+
+// クラウドのIoTブローカー向けに、秘密鍵で署名したJWTをパスワードとして使う
+// 有効期限が短いので、接続するたびに作り直す
+#[derive(Clone, Debug)]
+pub(crate) struct JwtOptions {
+    // PEM形式の秘密鍵 (RSAまたはPKCS#8のEC)
+    pub(crate) key_file: PathBuf,
+    pub(crate) algorithm: Algorithm,
+    pub(crate) audience: Option<String>,
+    pub(crate) lifetime: time::Duration,
+    // iat, exp, aud以外に追加するクレーム
+    pub(crate) claims: Vec<(String, String)>,
+}
+
+pub(crate) fn parse_jwt_algorithm(s: &str) -> Result<Algorithm, String> {
+    match s {
+        "RS256" => Ok(Algorithm::RS256),
+        "RS384" => Ok(Algorithm::RS384),
+        "RS512" => Ok(Algorithm::RS512),
+        "ES256" => Ok(Algorithm::ES256),
+        "ES384" => Ok(Algorithm::ES384),
+        _ => Err(format!(
+            "unsupported algorithm: {} (RS256, RS384, RS512, ES256, ES384)",
+            s
+        )),
+    }
+}
+
+pub(crate) fn parse_claim(claim: &str) -> Result<(String, String), String> {
+    match claim.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("Invalid claim: '{}'. (NAME=VALUE)", claim)),
+    }
+}
+
+// 鍵の差し替えに追従できるように、毎回ファイルから読む
+pub(crate) fn generate_jwt(options: &JwtOptions) -> io::Result<String> {
+    let invalid_key = |e: jsonwebtoken::errors::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Invalid JWT key. path={}, error={}",
+                options.key_file.display(),
+                e
+            ),
+        )
+    };
+    let pem = fs::read(&options.key_file).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "Failed to read JWT key. path={}, error={}",
+                options.key_file.display(),
+                e
+            ),
+        )
+    })?;
+    let key = match options.algorithm {
+        Algorithm::ES256 | Algorithm::ES384 => EncodingKey::from_ec_pem(&pem),
+        _ => EncodingKey::from_rsa_pem(&pem),
+    }
+    .map_err(invalid_key)?;
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(io::Error::other)?
+        .as_secs();
+    let mut claims = serde_json::Map::new();
+    for (name, value) in &options.claims {
+        claims.insert(name.clone(), value.clone().into());
+    }
+    claims.insert("iat".to_string(), now.into());
+    claims.insert("exp".to_string(), (now + options.lifetime.as_secs()).into());
+    if let Some(audience) = &options.audience {
+        claims.insert("aud".to_string(), audience.clone().into());
+    }
+
+    jsonwebtoken::encode(&Header::new(options.algorithm), &claims, &key).map_err(invalid_key)
+}
@@ -0,0 +1,572 @@
+use core::time;
+use log::{debug, info, warn};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{mpsc, Arc, Condvar, Mutex},
+    thread,
+    time::Instant,
+};
+
+use crate::{
+    client::{self, Client},
+    packet::{self, PacketType},
+    qos::QoS,
+    topic::TopicFilter,
+};
+
+// 送信要求とタイマーを確認する間隔
+const TICK: time::Duration = time::Duration::from_millis(20);
+
+// 完了通知を受け取るチャネル (処理が終わる前にイベントループが止まった場合は受信エラーになる)
+pub(crate) type Token<T> = mpsc::Receiver<io::Result<T>>;
+
+// 切断中に届いたPUBLISHを溜めておくキュー (再接続してCONNACKを受信した後に順番に送る)
+#[derive(Clone, Debug)]
+pub(crate) struct OfflineQueue {
+    // 溜めておくメッセージ数の上限
+    pub(crate) capacity: usize,
+    // キューが一杯のときの動作
+    pub(crate) policy: OverflowPolicy,
+    // falseの場合、切断中のQoS0のメッセージは溜めずに失敗させる
+    pub(crate) include_qos0: bool,
+}
+
+impl Default for OfflineQueue {
+    fn default() -> Self {
+        Self {
+            capacity: 100,
+            policy: OverflowPolicy::DropOldest,
+            include_qos0: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OverflowPolicy {
+    // 一番古いメッセージを捨てて、新しいメッセージを溜める
+    DropOldest,
+    // 新しいメッセージを捨てる
+    DropNewest,
+    // 空きができるまでHandle::publish()を止める
+    Block,
+}
+
+pub(crate) fn parse_overflow_policy(policy: &str) -> Result<OverflowPolicy, String> {
+    match policy {
+        "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+        "drop-newest" => Ok(OverflowPolicy::DropNewest),
+        "block" => Ok(OverflowPolicy::Block),
+        _ => Err(format!(
+            "Invalid policy: '{}'. (drop-oldest, drop-newest, block)",
+            policy
+        )),
+    }
+}
+
+type Completion<T> = mpsc::Sender<io::Result<T>>;
+
+// SUBACKを待っているトピックフィルター (購読し直す場合は完了通知がない)
+type UnackedSubscribe = (
+    Vec<(TopicFilter, QoS)>,
+    Option<Completion<Vec<(TopicFilter, QoS)>>>,
+);
+
+enum Request {
+    Publish(packet::PublishPacket, Completion<()>),
+    Subscribe(Vec<(TopicFilter, QoS)>, Completion<Vec<(TopicFilter, QoS)>>),
+    Unsubscribe(Vec<TopicFilter>, Completion<()>),
+    Disconnect(time::Duration),
+}
+
+// OverflowPolicy::Blockの場合に、キューが一杯の間はHandle::publish()を止める
+#[derive(Default)]
+struct Backpressure {
+    full: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Backpressure {
+    fn set(&self, full: bool) {
+        let mut current = self.full.lock().unwrap();
+        if *current != full {
+            *current = full;
+            self.condvar.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        let mut full = self.full.lock().unwrap();
+        while *full {
+            full = self.condvar.wait(full).unwrap();
+        }
+    }
+}
+
+// イベントループへの送信要求の窓口
+// ソケットへの書き込みはイベントループのスレッドだけが行うので、複数のスレッドから送信してもパケットが混ざらない
+#[derive(Clone)]
+pub(crate) struct Handle {
+    requests: mpsc::Sender<Request>,
+    backpressure: Arc<Backpressure>,
+}
+
+impl Handle {
+    // QoS0は送信した時点で、QoS1はPUBACK, QoS2はPUBCOMPを受信した時点で完了する
+    // 切断中はOfflineQueueに溜め、再接続した後に送信する
+    pub(crate) fn publish(&self, publish_packet: packet::PublishPacket) -> Token<()> {
+        self.backpressure.wait();
+
+        let (sender, token) = mpsc::channel();
+        self.request(Request::Publish(publish_packet, sender));
+        token
+    }
+
+    pub(crate) fn subscribe(
+        &self,
+        topic_filters: Vec<(TopicFilter, QoS)>,
+    ) -> Token<Vec<(TopicFilter, QoS)>> {
+        let (sender, token) = mpsc::channel();
+        self.request(Request::Subscribe(topic_filters, sender));
+        token
+    }
+
+    pub(crate) fn unsubscribe(&self, topic_filters: Vec<TopicFilter>) -> Token<()> {
+        let (sender, token) = mpsc::channel();
+        self.request(Request::Unsubscribe(topic_filters, sender));
+        token
+    }
+
+    // 以降の送信要求は受け付けず、UNSUBACKやQoS1, QoS2のACKをtimeoutまで待ってから切断する
+    pub(crate) fn disconnect(&self, timeout: time::Duration) {
+        self.request(Request::Disconnect(timeout));
+    }
+
+    fn request(&self, request: Request) {
+        // イベントループが止まっていれば、Tokenの受信エラーで呼び出し元に伝わる
+        let _ = self.requests.send(request);
+    }
+}
+
+// Clientの所有権をイベントループのスレッドに移す (接続していないClientでもよい)
+// 受信したメッセージは返り値のReceiverに届き、イベントループが止まると閉じられる
+// QoS1, QoS2のPUBLISHはmax_in_flight個までACKを待たずに送り、それを超えた分は送信要求の順に待たせる
+// 接続が切れた場合は再接続し、ブローカーがセッションを保持していなければ購読し直す
+pub(crate) fn spawn(
+    mut client: Client,
+    max_in_flight: usize,
+    offline_queue: OfflineQueue,
+) -> io::Result<(
+    Handle,
+    mpsc::Receiver<packet::PublishPacket>,
+    thread::JoinHandle<io::Result<()>>,
+)> {
+    client.set_poll_interval(Some(TICK))?;
+
+    let (requests, request_receiver) = mpsc::channel();
+    let (message_sender, messages) = mpsc::channel();
+    let backpressure = Arc::new(Backpressure::default());
+    let join_handle = {
+        let backpressure = backpressure.clone();
+        thread::spawn(move || {
+            let result = EventLoop {
+                client,
+                requests: request_receiver,
+                messages: message_sender,
+                max_in_flight: max_in_flight.max(1),
+                offline_queue,
+                backpressure: backpressure.clone(),
+                reconnect_at: Instant::now(),
+                backoff: client::MIN_BACKOFF,
+                subscriptions: vec![],
+                queued_publishes: VecDeque::new(),
+                unacked_publishes: HashMap::new(),
+                unacked_subscribes: HashMap::new(),
+                unacked_unsubscribes: HashMap::new(),
+            }
+            .run();
+            if let Err(e) = &result {
+                warn!("Event loop stopped. error={}", e);
+            }
+            // 止まっているHandle::publish()を動かす (送信要求はTokenの受信エラーになる)
+            backpressure.set(false);
+            result
+        })
+    };
+
+    Ok((
+        Handle {
+            requests,
+            backpressure,
+        },
+        messages,
+        join_handle,
+    ))
+}
+
+struct EventLoop {
+    client: Client,
+    requests: mpsc::Receiver<Request>,
+    messages: mpsc::Sender<packet::PublishPacket>,
+    max_in_flight: usize,
+    offline_queue: OfflineQueue,
+    backpressure: Arc<Backpressure>,
+    // 切断中に次に再接続を試みる時刻と、失敗した場合に次まで待つ時間
+    reconnect_at: Instant,
+    backoff: time::Duration,
+    // 再接続時に購読し直すトピックフィルター
+    subscriptions: Vec<(TopicFilter, QoS)>,
+    queued_publishes: VecDeque<(packet::PublishPacket, Completion<()>)>,
+    unacked_publishes: HashMap<u16, Completion<()>>,
+    unacked_subscribes: HashMap<u16, UnackedSubscribe>,
+    unacked_unsubscribes: HashMap<u16, Completion<()>>,
+}
+
+impl EventLoop {
+    fn run(&mut self) -> io::Result<()> {
+        // 切断処理中であれば、ACKを待つ期限
+        let mut deadline: Option<Instant> = None;
+
+        loop {
+            if !self.client.is_connected() && Instant::now() >= self.reconnect_at {
+                self.reconnect();
+            }
+
+            // 送信要求
+            loop {
+                let result = match self.requests.try_recv() {
+                    Ok(Request::Disconnect(timeout)) => {
+                        if deadline.is_none() {
+                            info!("Shutting down. Waiting for acknowledgements.");
+                            deadline = Some(Instant::now() + timeout);
+                        }
+                        Ok(())
+                    }
+                    // Handleがすべて破棄された場合も、同じように切断する
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        deadline.get_or_insert_with(|| Instant::now() + client::SHUTDOWN_TIMEOUT);
+                        break;
+                    }
+                    Ok(request) if deadline.is_some() => {
+                        reject(request, "Client is shutting down");
+                        Ok(())
+                    }
+                    Ok(request) => self.handle_request(request),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                };
+                if let Err(e) = result {
+                    self.connection_lost(e);
+                }
+            }
+            self.backpressure.set(
+                self.offline_queue.policy == OverflowPolicy::Block
+                    && !self.client.is_connected()
+                    && self.queued_publishes.len() >= self.offline_queue.capacity
+                    && deadline.is_none(),
+            );
+
+            if let Some(deadline) = deadline {
+                if !self.has_pending() {
+                    return self.disconnect();
+                }
+                if Instant::now() >= deadline {
+                    warn!("Shutdown timed out. Disconnect without waiting for acknowledgements.");
+                    self.fail_pending();
+                    return self.disconnect();
+                }
+            }
+
+            if !self.client.is_connected() {
+                thread::sleep(TICK);
+                continue;
+            }
+            if let Err(e) = self.process() {
+                self.connection_lost(e);
+            }
+        }
+    }
+
+    // 接続中の送受信とタイマーの処理
+    fn process(&mut self) -> io::Result<()> {
+        self.send_queued_publishes()?;
+
+        // タイマー (keep aliveはreceive()の中で処理する)
+        for packet_id in self.client.retransmit()? {
+            if let Some(completion) = self.unacked_publishes.remove(&packet_id) {
+                let _ = completion.send(Err(client::gave_up_error(packet_id)));
+            }
+        }
+
+        // 受信
+        if let Some(received_packet) = self.client.receive()? {
+            self.handle_received_packet(received_packet);
+        }
+        while let Some(publish_packet) = self.client.take_message() {
+            let _ = self.messages.send(publish_packet);
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> io::Result<()> {
+        if !self.client.is_connected() {
+            return Ok(());
+        }
+        self.client.disconnect()
+    }
+
+    // ACKが届いていないPUBLISH, PUBRELはClient::reconnect()が再送し、溜めておいたPUBLISHはprocess()で送る
+    fn reconnect(&mut self) {
+        let result = self.client.reconnect().and_then(|_| {
+            if self.client.session_present() || self.subscriptions.is_empty() {
+                return Ok(());
+            }
+            info!("Resubscribe topic_filters={:?}", self.subscriptions);
+            let packet_id = self.client.send_subscribe(self.subscriptions.clone())?;
+            self.unacked_subscribes
+                .insert(packet_id, (self.subscriptions.clone(), None));
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => self.backoff = client::MIN_BACKOFF,
+            Err(e) => {
+                warn!(
+                    "Failed to connect broker. Retry after {:?}. error={}",
+                    self.backoff, e
+                );
+                self.client.close();
+                self.reconnect_at = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(client::MAX_BACKOFF);
+            }
+        }
+    }
+
+    // ACK待ちのPUBLISH, PUBRELは再接続時に再送されるので、SUBSCRIBEとUNSUBSCRIBEだけ失敗させる
+    fn connection_lost(&mut self, error: io::Error) {
+        warn!("Lost connection to broker. error={}", error);
+        self.client.close();
+        self.reconnect_at = Instant::now();
+
+        let error = || io::Error::new(io::ErrorKind::NotConnected, "Lost connection to broker");
+        for (_, (_, completion)) in self.unacked_subscribes.drain() {
+            if let Some(completion) = completion {
+                let _ = completion.send(Err(error()));
+            }
+        }
+        for (_, completion) in self.unacked_unsubscribes.drain() {
+            let _ = completion.send(Err(error()));
+        }
+    }
+
+    fn handle_request(&mut self, request: Request) -> io::Result<()> {
+        // 送れないメッセージはキューに溜めずに、すぐにエラーを返す
+        if let Request::Publish(publish_packet, completion) = &request {
+            if let Err(e) = self.client.check_packet_size(publish_packet) {
+                let _ = completion.send(Err(e));
+                return Ok(());
+            }
+        }
+
+        if !self.client.is_connected() {
+            match request {
+                Request::Publish(publish_packet, completion) => {
+                    self.queue_offline(publish_packet, completion);
+                }
+                request => reject(request, "Not connected to broker"),
+            }
+            return Ok(());
+        }
+
+        match request {
+            // 順番を守るため、QoS0も待っているメッセージの後ろに並べる
+            Request::Publish(publish_packet, completion) => {
+                self.queued_publishes
+                    .push_back((publish_packet, completion));
+                self.send_queued_publishes()?;
+            }
+            Request::Subscribe(topic_filters, completion) => {
+                match self.client.send_subscribe(topic_filters.clone()) {
+                    Ok(packet_id) => {
+                        self.unacked_subscribes
+                            .insert(packet_id, (topic_filters, Some(completion)));
+                    }
+                    Err(e) => {
+                        let _ = completion.send(Err(io::Error::new(e.kind(), e.to_string())));
+                        return Err(e);
+                    }
+                }
+            }
+            Request::Unsubscribe(topic_filters, completion) => {
+                self.subscriptions
+                    .retain(|(topic_filter, _)| !topic_filters.contains(topic_filter));
+                match self.client.send_unsubscribe(topic_filters) {
+                    Ok(packet_id) => {
+                        self.unacked_unsubscribes.insert(packet_id, completion);
+                    }
+                    Err(e) => {
+                        let _ = completion.send(Err(io::Error::new(e.kind(), e.to_string())));
+                        return Err(e);
+                    }
+                }
+            }
+            Request::Disconnect(_) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn queue_offline(&mut self, publish_packet: packet::PublishPacket, completion: Completion<()>) {
+        let drop = |completion: Completion<()>| {
+            let _ = completion.send(Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Dropped from offline queue",
+            )));
+        };
+
+        if publish_packet.qos == QoS::QoS0 && !self.offline_queue.include_qos0 {
+            return drop(completion);
+        }
+        if self.queued_publishes.len() >= self.offline_queue.capacity {
+            match self.offline_queue.policy {
+                OverflowPolicy::DropOldest => match self.queued_publishes.pop_front() {
+                    Some((_, oldest)) => {
+                        warn!("Offline queue is full. Drop the oldest message.");
+                        drop(oldest);
+                    }
+                    None => return drop(completion),
+                },
+                OverflowPolicy::DropNewest => {
+                    warn!("Offline queue is full. Drop the newest message.");
+                    return drop(completion);
+                }
+                // Handle::publish()が止まる前に届いた分は、上限を超えても溜める
+                OverflowPolicy::Block => {}
+            }
+        }
+        self.queued_publishes
+            .push_back((publish_packet, completion));
+    }
+
+    // 送れるだけのPUBLISHをまとめて1回で書き込む
+    fn send_queued_publishes(&mut self) -> io::Result<()> {
+        let mut sent_qos0 = vec![];
+        while let Some((publish_packet, _)) = self.queued_publishes.front() {
+            if publish_packet.packet_id.is_some()
+                && self.unacked_publishes.len() >= self.max_in_flight
+            {
+                break;
+            }
+            let (mut publish_packet, completion) = self.queued_publishes.pop_front().unwrap();
+
+            match publish_packet.packet_id {
+                Some(mut packet_id) => {
+                    // ACKを待っているメッセージとパケットIDが重なると、ACKの対応が取れなくなる
+                    while self.unacked_publishes.contains_key(&packet_id)
+                        || self.client.is_in_flight(packet_id)
+                    {
+                        packet_id = packet::generate_packet_id();
+                    }
+                    publish_packet.packet_id = Some(packet_id);
+                    // 送信に失敗してもClientが保持しているので、再接続時に再送される
+                    self.unacked_publishes.insert(packet_id, completion);
+                    self.client.queue_publish(publish_packet)?;
+                }
+                None => {
+                    self.client.queue_publish(publish_packet)?;
+                    sent_qos0.push(completion);
+                }
+            }
+        }
+
+        // QoS0は書き込めた時点で完了する
+        let result = self.client.flush_writes();
+        for completion in sent_qos0 {
+            let _ = completion.send(match &result {
+                Ok(()) => Ok(()),
+                Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            });
+        }
+        result
+    }
+
+    // ACKを待っている要求や、途中で止まっているQoS2のやり取りがあるか
+    fn has_pending(&self) -> bool {
+        !self.queued_publishes.is_empty()
+            || !self.unacked_subscribes.is_empty()
+            || !self.unacked_unsubscribes.is_empty()
+            || self.client.has_in_flight()
+    }
+
+    fn fail_pending(&mut self) {
+        let error = || io::Error::new(io::ErrorKind::TimedOut, "Disconnected before acknowledged");
+        for (_, completion) in self.queued_publishes.drain(..) {
+            let _ = completion.send(Err(error()));
+        }
+        for (_, completion) in self.unacked_publishes.drain() {
+            let _ = completion.send(Err(error()));
+        }
+        for (_, (_, completion)) in self.unacked_subscribes.drain() {
+            if let Some(completion) = completion {
+                let _ = completion.send(Err(error()));
+            }
+        }
+        for (_, completion) in self.unacked_unsubscribes.drain() {
+            let _ = completion.send(Err(error()));
+        }
+    }
+
+    fn handle_received_packet(&mut self, received_packet: PacketType) {
+        match received_packet {
+            PacketType::PUBACK(packet::PubackPacket { packet_id })
+            | PacketType::PUBCOMP(packet::PubcompPacket { packet_id }) => {
+                if let Some(completion) = self.unacked_publishes.remove(&packet_id) {
+                    let _ = completion.send(Ok(()));
+                }
+            }
+            PacketType::SUBACK(suback_packet) => {
+                match self.unacked_subscribes.remove(&suback_packet.packet_id) {
+                    Some((topic_filters, completion)) => {
+                        let packet_id = suback_packet.packet_id;
+                        let result = client::check_suback(packet_id, &topic_filters, suback_packet);
+                        if result.is_ok() {
+                            for (topic_filter, qos) in topic_filters {
+                                self.subscriptions.retain(|(t, _)| *t != topic_filter);
+                                self.subscriptions.push((topic_filter, qos));
+                            }
+                        }
+                        match (completion, result) {
+                            (Some(completion), result) => {
+                                let _ = completion.send(result);
+                            }
+                            (None, Err(e)) => warn!("Failed to resubscribe. error={}", e),
+                            (None, Ok(_)) => {}
+                        }
+                    }
+                    None => debug!("Unknown suback_packet={:?}", suback_packet),
+                }
+            }
+            PacketType::UNSUBACK(unsuback_packet) => {
+                match self.unacked_unsubscribes.remove(&unsuback_packet.packet_id) {
+                    Some(completion) => {
+                        let _ = completion.send(Ok(()));
+                    }
+                    None => warn!("Unknown unsuback_packet={:?}", unsuback_packet),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// 送信できない送信要求を失敗させる
+fn reject(request: Request, reason: &str) {
+    let error = || io::Error::new(io::ErrorKind::NotConnected, reason);
+    match request {
+        Request::Publish(_, completion) | Request::Unsubscribe(_, completion) => {
+            let _ = completion.send(Err(error()));
+        }
+        Request::Subscribe(_, completion) => {
+            let _ = completion.send(Err(error()));
+        }
+        Request::Disconnect(_) => {}
+    }
+}
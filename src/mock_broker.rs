@@ -0,0 +1,233 @@
+// テスト用のスクリプトで動くブローカー
+// メモリ上の通信路 (transport::memory) でClientとつなぎ、ネットワークなしでQoSの状態遷移を確かめる
+use core::time;
+use std::{
+    io::{Read, Write},
+    sync::mpsc,
+    thread,
+};
+
+use crate::{
+    packet::{self, Packet},
+    qos::QoS,
+    transport::{memory, Connector, Transport},
+};
+
+// 固定ヘッダーの上位4ビット
+pub(crate) const CONNECT: u8 = 1;
+pub(crate) const PUBLISH: u8 = 3;
+pub(crate) const PUBACK: u8 = 4;
+pub(crate) const PUBREC: u8 = 5;
+pub(crate) const PUBREL: u8 = 6;
+pub(crate) const PUBCOMP: u8 = 7;
+pub(crate) const SUBSCRIBE: u8 = 8;
+pub(crate) const UNSUBSCRIBE: u8 = 10;
+pub(crate) const PINGREQ: u8 = 12;
+pub(crate) const DISCONNECT: u8 = 14;
+
+// クライアントが何も送ってこない場合に、テストを止めずに失敗させるまでの時間
+const TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+type Reply = Box<dyn Fn(&[u8]) -> Vec<u8> + Send>;
+
+pub(crate) enum Step {
+    // 次に届くパケットの種類 (途中のPINGREQにはPINGRESPを返して読み飛ばす)
+    Expect(u8),
+    // 直前にExpectしたパケットから返信を作って送る
+    Reply(Reply),
+    Send(Vec<u8>),
+    // 接続を切る
+    Close,
+    // クライアントが接続を切るまで待つ (途中まで届いたパケットは捨てる)
+    ExpectClosed,
+    // 次のステップまで待つ (間隔をあけてパケットを送る場合)
+    Sleep(time::Duration),
+}
+
+pub(crate) fn expect(packet_type: u8) -> Step {
+    Step::Expect(packet_type)
+}
+
+pub(crate) fn connack(sp: bool) -> Step {
+    Step::Send(
+        packet::ConnackPacket {
+            sp,
+            accepted: true,
+            refused_reason: None,
+        }
+        .serialize(),
+    )
+}
+
+pub(crate) fn suback(maximum_qos: Option<QoS>) -> Step {
+    suback_with(vec![maximum_qos])
+}
+
+// トピックフィルターごとのリターンコード (Noneは失敗 0x80)
+pub(crate) fn suback_with(return_codes: Vec<Option<QoS>>) -> Step {
+    Step::Reply(Box::new(move |bytes| {
+        packet::SubackPacket {
+            packet_id: packet_id(bytes),
+            return_codes: return_codes.clone(),
+        }
+        .serialize()
+    }))
+}
+
+pub(crate) fn unsuback() -> Step {
+    Step::Reply(Box::new(|bytes| {
+        packet::UnsubackPacket {
+            packet_id: packet_id(bytes),
+        }
+        .serialize()
+    }))
+}
+
+pub(crate) fn puback() -> Step {
+    Step::Reply(Box::new(|bytes| {
+        packet::PubackPacket {
+            packet_id: packet_id(bytes),
+        }
+        .serialize()
+    }))
+}
+
+pub(crate) fn pubrec() -> Step {
+    Step::Reply(Box::new(|bytes| {
+        packet::PubrecPacket {
+            packet_id: packet_id(bytes),
+        }
+        .serialize()
+    }))
+}
+
+pub(crate) fn pubcomp() -> Step {
+    Step::Reply(Box::new(|bytes| {
+        packet::PubcompPacket {
+            packet_id: packet_id(bytes),
+        }
+        .serialize()
+    }))
+}
+
+pub(crate) fn publish(publish_packet: packet::PublishPacket) -> Step {
+    Step::Send(publish_packet.serialize())
+}
+
+pub(crate) fn pubrel(packet_id: u16) -> Step {
+    Step::Send(packet::PubrelPacket { packet_id }.serialize())
+}
+
+pub(crate) fn sleep(duration: time::Duration) -> Step {
+    Step::Sleep(duration)
+}
+
+pub(crate) fn close() -> Step {
+    Step::Close
+}
+
+pub(crate) fn expect_closed() -> Step {
+    Step::ExpectClosed
+}
+
+pub(crate) fn packet_id(bytes: &[u8]) -> u16 {
+    if bytes[0] >> 4 == PUBLISH {
+        return packet::PublishPacket::deserialize(bytes)
+            .0
+            .packet_id
+            .unwrap();
+    }
+    let (_, i) = packet::extract_remaining_length(bytes);
+    u16::from_be_bytes([bytes[i], bytes[i + 1]])
+}
+
+pub(crate) struct MockBroker {
+    handle: thread::JoinHandle<Vec<Vec<u8>>>,
+}
+
+impl MockBroker {
+    // スクリプトを最後まで実行するのを待ち、クライアントから受信したパケットをすべて返す
+    // スクリプトと違うパケットが届いた場合はpanicする
+    pub(crate) fn join(self) -> Vec<Vec<u8>> {
+        self.handle.join().expect("Mock broker script failed")
+    }
+}
+
+// sessionsは接続ごとのスクリプト (再接続するたびに次のスクリプトを実行する)
+pub(crate) fn start(sessions: Vec<Vec<Step>>) -> (Connector, MockBroker) {
+    let (sender, receiver) = mpsc::channel::<memory::MemoryTransport>();
+
+    let handle = thread::spawn(move || {
+        let mut received_packets = vec![];
+        for script in sessions {
+            let transport = receiver
+                .recv_timeout(TIMEOUT)
+                .expect("Client did not connect");
+            transport.set_read_timeout(Some(TIMEOUT)).unwrap();
+            run(transport, script, &mut received_packets);
+        }
+        received_packets
+    });
+
+    let connector: Connector = Box::new(move |_address| {
+        let (client, broker) = memory::pair();
+        sender
+            .send(broker)
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::ConnectionRefused))?;
+        Ok(Box::new(client))
+    });
+
+    (connector, MockBroker { handle })
+}
+
+fn run(
+    mut transport: memory::MemoryTransport,
+    script: Vec<Step>,
+    received_packets: &mut Vec<Vec<u8>>,
+) {
+    let mut buffer = vec![];
+    let mut last_packet = vec![];
+
+    for step in script {
+        match step {
+            Step::Expect(packet_type) => loop {
+                let bytes = read_packet(&mut transport, &mut buffer);
+                received_packets.push(bytes.clone());
+
+                if bytes[0] >> 4 == PINGREQ && packet_type != PINGREQ {
+                    transport
+                        .write_all(&packet::PingrespPacket {}.serialize())
+                        .unwrap();
+                    continue;
+                }
+                assert_eq!(bytes[0] >> 4, packet_type, "Unexpected packet={:?}", bytes);
+                last_packet = bytes;
+                break;
+            },
+            Step::Reply(reply) => transport.write_all(&reply(&last_packet)).unwrap(),
+            Step::Send(bytes) => transport.write_all(&bytes).unwrap(),
+            Step::Close => break,
+            Step::Sleep(duration) => thread::sleep(duration),
+            Step::ExpectClosed => {
+                let mut bytes = [0; 1024];
+                while transport.read(&mut bytes).expect("Client did not close") > 0 {}
+                break;
+            }
+        }
+    }
+}
+
+fn read_packet(transport: &mut memory::MemoryTransport, buffer: &mut Vec<u8>) -> Vec<u8> {
+    loop {
+        if let Some(length) =
+            packet::complete_packet_length(buffer).expect("Invalid packet from client")
+        {
+            return buffer.drain(..length).collect();
+        }
+
+        let mut bytes = [0; 1024];
+        let size = transport.read(&mut bytes).expect("No packet from client");
+        assert!(size > 0, "Connection closed by client");
+        buffer.extend(&bytes[..size]);
+    }
+}
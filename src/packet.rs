@@ -1,10 +1,35 @@
+use bytes::{BufMut, Bytes};
 use rand::prelude::*;
-use std::{any::Any, fmt::Debug};
+use std::{
+    any::Any,
+    fmt::{self, Debug},
+    io,
+};
 
-use crate::qos::QoS;
+use crate::{
+    qos::QoS,
+    topic::{TopicFilter, TopicName},
+};
 
 pub(crate) trait Packet: Any + Debug + 'static {
-    fn serialize(&self) -> Vec<u8>;
+    // 可変ヘッダーとペイロードのバイト数
+    fn remaining_length(&self) -> usize;
+
+    // remaining lengthを先に求めておき、固定ヘッダーから順にbufへ書き込む
+    fn encode(&self, buf: &mut impl BufMut);
+
+    // 固定ヘッダーを含めたバイト数
+    fn encoded_len(&self) -> usize {
+        packet_size(self.remaining_length())
+    }
+
+    // テストやモックブローカーでバイト列を組み立てる用
+    #[cfg(test)]
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        self.encode(&mut bytes);
+        bytes
+    }
 
     // 2つの返り値は、(デシリアライズしたオブジェクト, デシリアライズしたバッファバイト数)
     fn deserialize(buf: &[u8]) -> (Self, usize)
@@ -12,6 +37,7 @@ pub(crate) trait Packet: Any + Debug + 'static {
         Self: Sized;
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub(crate) enum PacketType {
     CONNECT(ConnectPacket),
@@ -30,23 +56,89 @@ pub(crate) enum PacketType {
     DISCONNECT(DisconnectPacket),
 }
 
+impl PacketType {
+    // ブローカーから届くパケットだけをデコードする (それ以外はエラー)
+    // PUBLISHはbufを共有してデコードする (それ以外は小さいのでコピーする)
+    pub(crate) fn decode(buf: &Bytes) -> io::Result<(Self, usize)> {
+        let (packet, size) = match buf[0] >> 4 {
+            2 => {
+                let (packet, size) = ConnackPacket::deserialize(buf);
+                (PacketType::CONNACK(packet), size)
+            }
+            3 => {
+                let (packet, size) = PublishPacket::decode(buf)?;
+                (PacketType::PUBLISH(packet), size)
+            }
+            4 => {
+                let (packet, size) = PubackPacket::deserialize(buf);
+                (PacketType::PUBACK(packet), size)
+            }
+            5 => {
+                let (packet, size) = PubrecPacket::deserialize(buf);
+                (PacketType::PUBREC(packet), size)
+            }
+            6 => {
+                let (packet, size) = PubrelPacket::deserialize(buf);
+                (PacketType::PUBREL(packet), size)
+            }
+            7 => {
+                let (packet, size) = PubcompPacket::deserialize(buf);
+                (PacketType::PUBCOMP(packet), size)
+            }
+            9 => {
+                let (packet, size) = SubackPacket::deserialize(buf);
+                (PacketType::SUBACK(packet), size)
+            }
+            11 => {
+                let (packet, size) = UnsubackPacket::deserialize(buf);
+                (PacketType::UNSUBACK(packet), size)
+            }
+            13 => {
+                let (packet, size) = PingrespPacket::deserialize(buf);
+                (PacketType::PINGRESP(packet), size)
+            }
+            packet_type => return Err(unexpected_packet_type(packet_type)),
+        };
+        Ok((packet, size))
+    }
+}
+
 impl Packet for PacketType {
-    fn serialize(&self) -> Vec<u8> {
+    fn remaining_length(&self) -> usize {
         match self {
-            PacketType::CONNECT(packet) => packet.serialize(),
-            PacketType::CONNACK(packet) => packet.serialize(),
-            PacketType::PUBLISH(packet) => packet.serialize(),
-            PacketType::PUBACK(packet) => packet.serialize(),
-            PacketType::PUBREC(packet) => packet.serialize(),
-            PacketType::PUBREL(packet) => packet.serialize(),
-            PacketType::PUBCOMP(packet) => packet.serialize(),
-            PacketType::SUBSCRIBE(packet) => packet.serialize(),
-            PacketType::SUBACK(packet) => packet.serialize(),
-            PacketType::UNSUBSCRIBE(packet) => packet.serialize(),
-            PacketType::UNSUBACK(packet) => packet.serialize(),
-            PacketType::PINGREQ(packet) => packet.serialize(),
-            PacketType::PINGRESP(packet) => packet.serialize(),
-            PacketType::DISCONNECT(packet) => packet.serialize(),
+            PacketType::CONNECT(packet) => packet.remaining_length(),
+            PacketType::CONNACK(packet) => packet.remaining_length(),
+            PacketType::PUBLISH(packet) => packet.remaining_length(),
+            PacketType::PUBACK(packet) => packet.remaining_length(),
+            PacketType::PUBREC(packet) => packet.remaining_length(),
+            PacketType::PUBREL(packet) => packet.remaining_length(),
+            PacketType::PUBCOMP(packet) => packet.remaining_length(),
+            PacketType::SUBSCRIBE(packet) => packet.remaining_length(),
+            PacketType::SUBACK(packet) => packet.remaining_length(),
+            PacketType::UNSUBSCRIBE(packet) => packet.remaining_length(),
+            PacketType::UNSUBACK(packet) => packet.remaining_length(),
+            PacketType::PINGREQ(packet) => packet.remaining_length(),
+            PacketType::PINGRESP(packet) => packet.remaining_length(),
+            PacketType::DISCONNECT(packet) => packet.remaining_length(),
+        }
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        match self {
+            PacketType::CONNECT(packet) => packet.encode(buf),
+            PacketType::CONNACK(packet) => packet.encode(buf),
+            PacketType::PUBLISH(packet) => packet.encode(buf),
+            PacketType::PUBACK(packet) => packet.encode(buf),
+            PacketType::PUBREC(packet) => packet.encode(buf),
+            PacketType::PUBREL(packet) => packet.encode(buf),
+            PacketType::PUBCOMP(packet) => packet.encode(buf),
+            PacketType::SUBSCRIBE(packet) => packet.encode(buf),
+            PacketType::SUBACK(packet) => packet.encode(buf),
+            PacketType::UNSUBSCRIBE(packet) => packet.encode(buf),
+            PacketType::UNSUBACK(packet) => packet.encode(buf),
+            PacketType::PINGREQ(packet) => packet.encode(buf),
+            PacketType::PINGRESP(packet) => packet.encode(buf),
+            PacketType::DISCONNECT(packet) => packet.encode(buf),
         }
     }
 
@@ -116,7 +208,37 @@ impl Packet for PacketType {
     }
 }
 
-#[derive(Clone, Debug)]
+// MQTT 3.1.1でブローカーが必ず受け付けるクライアントIDは、0-9a-zA-Zの1〜23文字 (それ以外はブローカー次第)
+pub(crate) const MAX_CLIENT_ID_LENGTH: usize = 23;
+
+pub(crate) fn is_portable_client_id(client_id: &str) -> bool {
+    (1..=MAX_CLIENT_ID_LENGTH).contains(&client_id.len())
+        && client_id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+// どのブローカーでも受け付けられる範囲で、できるだけ長いIDを作る
+pub(crate) fn generate_client_id() -> String {
+    const PREFIX: &str = "mqttclient";
+    let mut rng = rand::thread_rng();
+    let suffix: String = (0..MAX_CLIENT_ID_LENGTH - PREFIX.len())
+        .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
+        .collect();
+    format!("{}{}", PREFIX, suffix)
+}
+
+// パスワードやWillメッセージをログに出さないように、値の有無だけを表示する
+pub(crate) struct Redacted<'a>(pub(crate) &'a Option<String>);
+
+impl Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("Some(<redacted>)"),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub(crate) struct ConnectPacket {
     pub(crate) client_id: String,
     pub(crate) username: Option<String>,
@@ -126,11 +248,29 @@ pub(crate) struct ConnectPacket {
     pub(crate) will_flag: bool,
     pub(crate) clean_session: bool,
     pub(crate) keep_alive: u16,
-    pub(crate) will_topic: Option<String>,
+    pub(crate) will_topic: Option<TopicName>,
     pub(crate) will_message: Option<String>,
 }
 
+impl Debug for ConnectPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectPacket")
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("password", &Redacted(&self.password))
+            .field("qos", &self.qos)
+            .field("will_retain", &self.will_retain)
+            .field("will_flag", &self.will_flag)
+            .field("clean_session", &self.clean_session)
+            .field("keep_alive", &self.keep_alive)
+            .field("will_topic", &self.will_topic)
+            .field("will_message", &Redacted(&self.will_message))
+            .finish()
+    }
+}
+
 impl ConnectPacket {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         username: Option<String>,
         password: Option<String>,
@@ -138,13 +278,20 @@ impl ConnectPacket {
         keep_alive: u16,
         clean_session: bool,
         will_flag: bool,
-        will_topic: Option<String>,
+        will_topic: Option<TopicName>,
         will_message: Option<String>,
-    ) -> Self {
-        let client_id = client_id.clone().unwrap_or_else(|| {
-            let mut rng = rand::thread_rng();
-            format!("mqttclient{}", rng.gen::<u16>())
-        });
+    ) -> io::Result<Self> {
+        let client_id = client_id.clone().unwrap_or_else(generate_client_id);
+        check_str_length("client ID", &client_id)?;
+        for (field, value) in [
+            ("username", &username),
+            ("password", &password),
+            ("will message", &will_message),
+        ] {
+            if let Some(value) = value {
+                check_str_length(field, value)?;
+            }
+        }
 
         if username.is_none() && password.is_some() {
             panic!("password must not be specified if username is not specified");
@@ -154,7 +301,7 @@ impl ConnectPacket {
             panic!("will_topic and will_message must be specified if will_flag is true");
         }
 
-        Self {
+        Ok(Self {
             client_id,
             username,
             password,
@@ -165,21 +312,43 @@ impl ConnectPacket {
             will_retain: false,
             will_topic,
             will_message,
-        }
+        })
     }
 }
 
 impl Packet for ConnectPacket {
-    fn serialize(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+    fn remaining_length(&self) -> usize {
+        // Protocol name (2 + 4), Protocol level, Control flags, Keep alive
+        let mut length = 6 + 1 + 1 + 2;
+        length += 2 + self.client_id.len();
+        if self.will_flag {
+            length += self
+                .will_topic
+                .as_ref()
+                .map_or(0, |will_topic| 2 + will_topic.as_str().len());
+            length += self
+                .will_message
+                .as_ref()
+                .map_or(0, |will_message| 2 + will_message.len());
+        }
+        if let Some(username) = &self.username {
+            length += 2 + username.len();
+            length += self
+                .password
+                .as_ref()
+                .map_or(0, |password| 2 + password.len());
+        }
+        length
+    }
 
+    fn encode(&self, buf: &mut impl BufMut) {
         // Fixed header
-        bytes.push(0b0001_0000); // CONNECT=1, 0000
+        buf.put_u8(0b0001_0000); // CONNECT=1, 0000
+        put_remaining_length(buf, self.remaining_length());
 
         // Variable header
-        bytes.extend((4 as u16).to_be_bytes()); // Protocol name length (4 bytes)
-        bytes.extend("MQTT".as_bytes()); // Protocol name
-        bytes.push(4); // Protocol level (3.1.1 => 4)
+        put_str(buf, "MQTT"); // Protocol name
+        buf.put_u8(4); // Protocol level (3.1.1 => 4)
 
         // Control flags
         let control_flag = (self.username.is_some() as u8) << 7
@@ -188,43 +357,32 @@ impl Packet for ConnectPacket {
             | (self.qos as u8) << 3
             | (self.will_flag as u8) << 2
             | (self.clean_session as u8) << 1;
-        bytes.push(control_flag);
+        buf.put_u8(control_flag);
 
-        bytes.extend(self.keep_alive.to_be_bytes()); // Keep alive MSB
+        buf.put_u16(self.keep_alive); // Keep alive
 
         // Payload
-        bytes.extend((self.client_id.len() as u16).to_be_bytes()); // Client ID length
-        bytes.extend(self.client_id.as_bytes()); // Client ID
+        put_str(buf, &self.client_id); // Client ID
 
         // Will topic, Will message
         if self.will_flag {
             if let Some(will_topic) = &self.will_topic {
-                bytes.extend((will_topic.len() as u16).to_be_bytes()); // Will topic length
-                bytes.extend(will_topic.as_bytes()); // Will topic
+                put_str(buf, will_topic.as_str());
             }
 
             if let Some(will_message) = &self.will_message {
-                bytes.extend((will_message.len() as u16).to_be_bytes()); // Will message length
-                bytes.extend(will_message.as_bytes()); // Will message
+                put_str(buf, will_message);
             }
         }
 
         // Username, Password
         if let Some(username) = &self.username {
-            bytes.extend((username.len() as u16).to_be_bytes()); // Username length
-            bytes.extend(username.as_bytes()); // Username
+            put_str(buf, username);
 
             if let Some(password) = &self.password {
-                bytes.extend((password.len() as u16).to_be_bytes()); // Password length
-                bytes.extend(password.as_bytes()); // Password
+                put_str(buf, password);
             }
         }
-
-        // Fixed header
-        // NOTE: 前もって計算するのが面倒なので、実際のサイズを後から挿入している
-        insert_remaining_length(&mut bytes);
-
-        bytes
     }
 
     fn deserialize(_buf: &[u8]) -> (Self, usize) {
@@ -232,6 +390,15 @@ impl Packet for ConnectPacket {
     }
 }
 
+// CONNACKのreturn code (1〜5) に対応する拒否理由
+const REFUSED_REASONS: [&str; 5] = [
+    "unacceptable protocol version",
+    "identifier rejected",
+    "server unavailable",
+    "bad user name or password",
+    "not authorized",
+];
+
 #[derive(Clone, Debug)]
 pub(crate) struct ConnackPacket {
     pub(crate) sp: bool,
@@ -240,26 +407,37 @@ pub(crate) struct ConnackPacket {
 }
 
 impl Packet for ConnackPacket {
-    fn serialize(&self) -> Vec<u8> {
-        unimplemented!()
+    fn remaining_length(&self) -> usize {
+        2
     }
 
-    fn deserialize(buf: &[u8]) -> (Self, usize) {
+    fn encode(&self, buf: &mut impl BufMut) {
+        let return_code = match self.refused_reason {
+            Some(reason) => REFUSED_REASONS.iter().position(|r| *r == reason).unwrap() as u8 + 1,
+            None => 0,
+        };
+
         // Fixed header
-        assert!(buf[0] == 0b00100000);
-        assert!(buf[1] == 0b00000010);
+        buf.put_u8(0b0010_0000); // CONNACK=2
+        buf.put_u8(2); // remaining length
 
         // Variable header
-        let sp = buf[2] & 0b00000001 == 1;
+        buf.put_u8(self.sp as u8);
+        buf.put_u8(return_code);
+    }
+
+    fn deserialize(buf: &[u8]) -> (Self, usize) {
+        // Fixed header (予約ビットや長さはconformance::checkで確かめる)
+        assert!(buf[0] >> 4 == 2);
+        let (remaining_length, i) = extract_remaining_length(buf);
+
+        // Variable header
+        let sp = buf[i] & 0b00000001 == 1;
         // NOTE: 本来はenumで表現すべきだが、エラーケースの処理はしないので文字列にしておく
-        let refused_reason = match buf[3] {
+        let refused_reason = match buf[i + 1] {
             0 => None,
-            1 => Some("unacceptable protocol version"),
-            2 => Some("identifier rejected"),
-            3 => Some("server unavailable"),
-            4 => Some("bad user name or password"),
-            5 => Some("not authorized"),
-            _ => unreachable!(),
+            code @ 1..=5 => Some(REFUSED_REASONS[code as usize - 1]),
+            _ => Some("Unknown return code"),
         };
 
         (
@@ -268,7 +446,7 @@ impl Packet for ConnackPacket {
                 accepted: refused_reason.is_none(),
                 refused_reason,
             },
-            4,
+            i + remaining_length,
         )
     }
 }
@@ -278,9 +456,10 @@ pub(crate) struct PublishPacket {
     pub(crate) dup: bool,
     pub(crate) qos: QoS,
     pub(crate) retain: bool,
-    pub(crate) topic_name: String,
+    pub(crate) topic_name: TopicName,
     pub(crate) packet_id: Option<u16>,
-    pub(crate) payload: Vec<u8>,
+    // 受信したPUBLISHでは、受信したバイト列を共有する (cloneしてもコピーしない)
+    pub(crate) payload: Bytes,
 }
 
 impl PublishPacket {
@@ -288,14 +467,12 @@ impl PublishPacket {
         dup: bool,
         qos: QoS,
         retain: bool,
-        topic_name: String,
+        topic_name: TopicName,
         mut packet_id: Option<u16>,
-        payload: Vec<u8>,
+        payload: impl Into<Bytes>,
     ) -> Self {
-        if qos == QoS::QoS2 || qos == QoS::QoS1 {
-            if packet_id.is_none() {
-                packet_id = Some(generate_packet_id());
-            }
+        if (qos == QoS::QoS2 || qos == QoS::QoS1) && packet_id.is_none() {
+            packet_id = Some(generate_packet_id());
         }
 
         Self {
@@ -304,42 +481,54 @@ impl PublishPacket {
             retain,
             topic_name,
             packet_id,
-            payload,
+            payload: payload.into(),
         }
     }
-}
-
-impl Packet for PublishPacket {
-    fn serialize(&self) -> Vec<u8> {
-        let mut bytes = vec![];
 
+    // ペイロードの手前まで (ペイロードはコピーせずに別に書き込めるように)
+    pub(crate) fn encode_header(&self, buf: &mut impl BufMut) {
         // Fixed header
-        bytes.push(
-            (0b0011_0000 as u8)
-                | (self.dup as u8) << 3
-                | (self.qos as u8) << 1
-                | (self.retain as u8),
+        buf.put_u8(
+            0b0011_0000 | (self.dup as u8) << 3 | (self.qos as u8) << 1 | (self.retain as u8),
         );
+        put_remaining_length(buf, self.remaining_length());
 
         // Variable header
-        bytes.extend((self.topic_name.len() as u16).to_be_bytes()); // topic length
-        bytes.extend(self.topic_name.as_bytes()); // topic name
+        put_str(buf, self.topic_name.as_str()); // topic name
         if let Some(packet_id) = self.packet_id {
-            bytes.extend(packet_id.to_be_bytes()); // packet id
+            buf.put_u16(packet_id); // packet id
         }
+    }
+}
 
-        // Payload
-        bytes.extend(self.payload.clone());
+impl Packet for PublishPacket {
+    fn remaining_length(&self) -> usize {
+        let packet_id_length = if self.packet_id.is_some() { 2 } else { 0 };
+        2 + self.topic_name.as_str().len() + packet_id_length + self.payload.len()
+    }
 
-        insert_remaining_length(&mut bytes);
+    fn encode(&self, buf: &mut impl BufMut) {
+        self.encode_header(buf);
 
-        bytes
+        // Payload
+        buf.put_slice(&self.payload);
     }
 
+    // テストやモックブローカーで、組み立てたバイト列を読む用 (受信したパケットはdecode()で読む)
     fn deserialize(buf: &[u8]) -> (Self, usize) {
+        let (remaining_length, i) = extract_remaining_length(buf);
+        Self::decode(&Bytes::copy_from_slice(&buf[..i + remaining_length]))
+            .expect("Invalid publish packet")
+    }
+}
+
+impl PublishPacket {
+    // トピック名とペイロードは、bufの一部をコピーせずに共有する
+    // トピック名が使えない文字列の場合はエラー
+    pub(crate) fn decode(buf: &Bytes) -> io::Result<(Self, usize)> {
         assert!(buf[0] & 0b1111_0000 == 0b0011_0000);
         let dup = buf[0] & 0b0000_1000 == 0b0000_1000;
-        let qos: QoS = ((buf[0] & 0b0000_0110) >> 1 as u8).into();
+        let qos: QoS = ((buf[0] & 0b0000_0110) >> 1).into();
         let retain = buf[0] & 0b0000_0001 == 0b0000_0001;
 
         let (remaining_length, mut i) = extract_remaining_length(buf);
@@ -347,19 +536,20 @@ impl Packet for PublishPacket {
 
         let topic_name_length = u16::from_be_bytes([buf[i], buf[i + 1]]);
         let topic_name =
-            String::from_utf8(buf[i + 2..i + 2 + topic_name_length as usize].to_vec()).unwrap();
+            TopicName::from_bytes(buf.slice(i + 2..i + 2 + topic_name_length as usize))?;
         i = i + 2 + topic_name_length as usize;
 
         let packet_id = if qos != QoS::QoS0 {
+            let packet_id = u16::from_be_bytes([buf[i], buf[i + 1]]);
             i += 2;
-            Some(u16::from_be_bytes([buf[i], buf[i + 1]]))
+            Some(packet_id)
         } else {
             None
         };
 
-        let payload = buf[i..i + (remaining_length - (2 + topic_name_length as usize))].to_vec();
+        let payload = buf.slice(i..fixed_header_length + remaining_length);
 
-        (
+        Ok((
             Self {
                 dup,
                 qos,
@@ -369,7 +559,7 @@ impl Packet for PublishPacket {
                 payload,
             },
             fixed_header_length + remaining_length,
-        )
+        ))
     }
 }
 
@@ -379,28 +569,28 @@ pub(crate) struct PubackPacket {
 }
 
 impl Packet for PubackPacket {
-    fn serialize(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+    fn remaining_length(&self) -> usize {
+        2
+    }
 
+    fn encode(&self, buf: &mut impl BufMut) {
         // Fixed header
-        bytes.push(0b01000000); // PUBACK=4
-        bytes.push(0b00000010); // remaining length
+        buf.put_u8(0b0100_0000); // PUBACK=4
+        buf.put_u8(2); // remaining length
 
         // Variable header
-        bytes.extend(self.packet_id.to_be_bytes());
-
-        bytes
+        buf.put_u16(self.packet_id);
     }
 
     fn deserialize(buf: &[u8]) -> (Self, usize) {
         // Fixed header
-        assert!(buf[0] == 0b01000000);
-        assert!(buf[1] == 0b00000010);
+        assert!(buf[0] >> 4 == 4);
+        let (remaining_length, i) = extract_remaining_length(buf);
 
         // Variable header
-        let packet_id = u16::from_be_bytes([buf[2], buf[3]]);
+        let packet_id = u16::from_be_bytes([buf[i], buf[i + 1]]);
 
-        (Self { packet_id }, 4)
+        (Self { packet_id }, i + remaining_length)
     }
 }
 
@@ -410,26 +600,26 @@ pub(crate) struct PubrecPacket {
 }
 
 impl Packet for PubrecPacket {
-    fn serialize(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+    fn remaining_length(&self) -> usize {
+        2
+    }
 
+    fn encode(&self, buf: &mut impl BufMut) {
         // Fixed header
-        bytes.push(0b0101_0000); // PUBREC=5
-        bytes.push(2); // remaining length
+        buf.put_u8(0b0101_0000); // PUBREC=5
+        buf.put_u8(2); // remaining length
 
         // Variable header
-        bytes.extend(self.packet_id.to_be_bytes());
-
-        bytes
+        buf.put_u16(self.packet_id);
     }
 
     fn deserialize(buf: &[u8]) -> (Self, usize) {
-        assert!(buf[0] == 0b0101_0000);
-        assert!(buf[1] == 2);
+        assert!(buf[0] >> 4 == 5);
+        let (remaining_length, i) = extract_remaining_length(buf);
 
-        let packet_id = u16::from_be_bytes([buf[2], buf[3]]);
+        let packet_id = u16::from_be_bytes([buf[i], buf[i + 1]]);
 
-        (Self { packet_id }, 4)
+        (Self { packet_id }, i + remaining_length)
     }
 }
 
@@ -439,26 +629,26 @@ pub(crate) struct PubrelPacket {
 }
 
 impl Packet for PubrelPacket {
-    fn serialize(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+    fn remaining_length(&self) -> usize {
+        2
+    }
 
+    fn encode(&self, buf: &mut impl BufMut) {
         // Fixed header
-        bytes.push(0b0110_0010); // PUBREL=6
-        bytes.push(2); // remaining length
+        buf.put_u8(0b0110_0010); // PUBREL=6
+        buf.put_u8(2); // remaining length
 
         // Variable header
-        bytes.extend(self.packet_id.to_be_bytes());
-
-        bytes
+        buf.put_u16(self.packet_id);
     }
 
     fn deserialize(buf: &[u8]) -> (Self, usize) {
-        assert!(buf[0] == 0b0110_0010);
-        assert!(buf[1] == 2);
+        assert!(buf[0] >> 4 == 6);
+        let (remaining_length, i) = extract_remaining_length(buf);
 
-        let packet_id = u16::from_be_bytes([buf[2], buf[3]]);
+        let packet_id = u16::from_be_bytes([buf[i], buf[i + 1]]);
 
-        (Self { packet_id }, 4)
+        (Self { packet_id }, i + remaining_length)
     }
 }
 
@@ -468,55 +658,58 @@ pub(crate) struct PubcompPacket {
 }
 
 impl Packet for PubcompPacket {
-    fn serialize(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+    fn remaining_length(&self) -> usize {
+        2
+    }
 
+    fn encode(&self, buf: &mut impl BufMut) {
         // Fixed header
-        bytes.push(0b0111_0000); // PUBCOMP=7
-        bytes.push(2); // remaining length
+        buf.put_u8(0b0111_0000); // PUBCOMP=7
+        buf.put_u8(2); // remaining length
 
         // Variable header
-        bytes.extend(self.packet_id.to_be_bytes());
-
-        bytes
+        buf.put_u16(self.packet_id);
     }
 
     fn deserialize(buf: &[u8]) -> (Self, usize) {
-        assert!(buf[0] == 0b0111_0000);
-        assert!(buf[1] == 2);
+        assert!(buf[0] >> 4 == 7);
+        let (remaining_length, i) = extract_remaining_length(buf);
 
-        let packet_id = u16::from_be_bytes([buf[2], buf[3]]);
+        let packet_id = u16::from_be_bytes([buf[i], buf[i + 1]]);
 
-        (Self { packet_id }, 4)
+        (Self { packet_id }, i + remaining_length)
     }
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct SubscribePacket {
     pub(crate) packet_id: u16,
-    pub(crate) topic_filters: Vec<(String, QoS)>,
+    pub(crate) topic_filters: Vec<(TopicFilter, QoS)>,
 }
 
 impl Packet for SubscribePacket {
-    fn serialize(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+    fn remaining_length(&self) -> usize {
+        let topic_filters: usize = self
+            .topic_filters
+            .iter()
+            .map(|(topic_filter, _)| 2 + topic_filter.as_str().len() + 1)
+            .sum();
+        2 + topic_filters
+    }
 
+    fn encode(&self, buf: &mut impl BufMut) {
         // Fixed header
-        bytes.push(0b1000_0010); // SUBSCRIBE=8
+        buf.put_u8(0b1000_0010); // SUBSCRIBE=8
+        put_remaining_length(buf, self.remaining_length());
 
         // Variable header
-        bytes.extend(self.packet_id.to_be_bytes());
+        buf.put_u16(self.packet_id);
 
         // Payload
-        for (topic_name, qos) in &self.topic_filters {
-            bytes.extend((topic_name.len() as u16).to_be_bytes()); // topic name length
-            bytes.extend(topic_name.as_bytes()); // topic name
-            bytes.push(*qos as u8); // Requested QoS
+        for (topic_filter, qos) in &self.topic_filters {
+            put_str(buf, topic_filter.as_str()); // topic filter
+            buf.put_u8(*qos as u8); // Requested QoS
         }
-
-        insert_remaining_length(&mut bytes);
-
-        bytes
     }
 
     fn deserialize(_buf: &[u8]) -> (Self, usize) {
@@ -527,36 +720,53 @@ impl Packet for SubscribePacket {
 #[derive(Clone, Debug)]
 pub(crate) struct SubackPacket {
     pub(crate) packet_id: u16,
-    pub(crate) maximum_qos: Option<QoS>,
-    pub(crate) failure: bool,
+    // SUBSCRIBEのトピックフィルターごとに許可されたQoS (Noneは0x80: 失敗)
+    pub(crate) return_codes: Vec<Option<QoS>>,
 }
 
 impl Packet for SubackPacket {
-    fn serialize(&self) -> Vec<u8> {
-        unimplemented!()
+    fn remaining_length(&self) -> usize {
+        2 + self.return_codes.len()
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Fixed header
+        buf.put_u8(0b1001_0000); // SUBACK=9
+        put_remaining_length(buf, self.remaining_length());
+
+        // Variable header
+        buf.put_u16(self.packet_id);
+
+        // Payload
+        for return_code in &self.return_codes {
+            match return_code {
+                Some(qos) => buf.put_u8(*qos as u8),
+                None => buf.put_u8(0x80),
+            }
+        }
     }
 
     fn deserialize(buf: &[u8]) -> (Self, usize) {
-        assert!(buf[0] == 0b10010000);
-        assert!(buf[1] == 3);
+        assert!(buf[0] >> 4 == 9);
 
-        let packet_id = u16::from_be_bytes([buf[2], buf[3]]);
+        let (remaining_length, i) = extract_remaining_length(buf);
+        let packet_id = u16::from_be_bytes([buf[i], buf[i + 1]]);
 
-        let (maximum_qos, failure) = match buf[4] {
-            0 => (Some(QoS::QoS0), false),
-            1 => (Some(QoS::QoS1), false),
-            2 => (Some(QoS::QoS2), false),
-            128 => (None, true),
-            _ => unreachable!(),
-        };
+        // 0, 1, 2以外 (0x80を含む) は失敗として扱う
+        let return_codes = buf[i + 2..i + remaining_length]
+            .iter()
+            .map(|code| match code {
+                0..=2 => Some(QoS::from(*code)),
+                _ => None,
+            })
+            .collect();
 
         (
             Self {
                 packet_id,
-                maximum_qos,
-                failure,
+                return_codes,
             },
-            5,
+            i + remaining_length,
         )
     }
 }
@@ -564,31 +774,34 @@ impl Packet for SubackPacket {
 #[derive(Clone, Debug)]
 pub(crate) struct UnsubscribePacket {
     pub(crate) packet_id: u16,
-    pub(crate) topic_filters: Vec<String>,
+    pub(crate) topic_filters: Vec<TopicFilter>,
 }
 
 impl Packet for UnsubscribePacket {
-    fn serialize(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+    fn remaining_length(&self) -> usize {
+        let topic_filters: usize = self
+            .topic_filters
+            .iter()
+            .map(|topic_filter| 2 + topic_filter.as_str().len())
+            .sum();
+        2 + topic_filters
+    }
 
+    fn encode(&self, buf: &mut impl BufMut) {
         // Fixed header
-        bytes.push(0b1010_0010); // UNSUBSCRIBE=10
+        buf.put_u8(0b1010_0010); // UNSUBSCRIBE=10
+        put_remaining_length(buf, self.remaining_length());
 
         // Variable header
-        bytes.extend(self.packet_id.to_be_bytes());
+        buf.put_u16(self.packet_id);
 
         // Payload
-        for topic in &self.topic_filters {
-            bytes.extend((topic.len() as u16).to_be_bytes());
-            bytes.extend(topic.as_bytes());
+        for topic_filter in &self.topic_filters {
+            put_str(buf, topic_filter.as_str());
         }
-
-        insert_remaining_length(&mut bytes);
-
-        bytes
     }
 
-    fn deserialize(buf: &[u8]) -> (Self, usize)
+    fn deserialize(_buf: &[u8]) -> (Self, usize)
     where
         Self: Sized,
     {
@@ -602,22 +815,31 @@ pub(crate) struct UnsubackPacket {
 }
 
 impl Packet for UnsubackPacket {
-    fn serialize(&self) -> Vec<u8> {
-        unimplemented!()
+    fn remaining_length(&self) -> usize {
+        2
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Fixed header
+        buf.put_u8(0b1011_0000); // UNSUBACK=11
+        buf.put_u8(2); // remaining length
+
+        // Variable header
+        buf.put_u16(self.packet_id);
     }
 
     fn deserialize(buf: &[u8]) -> (Self, usize)
     where
         Self: Sized,
     {
-        assert!(buf[0] == 0b1011_0000);
-        assert!(buf[1] == 2);
+        assert!(buf[0] >> 4 == 11);
+        let (remaining_length, i) = extract_remaining_length(buf);
 
         (
             Self {
-                packet_id: u16::from_be_bytes([buf[2], buf[3]]),
+                packet_id: u16::from_be_bytes([buf[i], buf[i + 1]]),
             },
-            4,
+            i + remaining_length,
         )
     }
 }
@@ -626,14 +848,14 @@ impl Packet for UnsubackPacket {
 pub(crate) struct PingreqPacket {}
 
 impl Packet for PingreqPacket {
-    fn serialize(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+    fn remaining_length(&self) -> usize {
+        0
+    }
 
+    fn encode(&self, buf: &mut impl BufMut) {
         // Fixed header
-        bytes.push(0b1100_0000); // PINGREQ=12
-        bytes.push(0); // remaining length
-
-        bytes
+        buf.put_u8(0b1100_0000); // PINGREQ=12
+        buf.put_u8(0); // remaining length
     }
 
     fn deserialize(_buf: &[u8]) -> (Self, usize)
@@ -648,18 +870,24 @@ impl Packet for PingreqPacket {
 pub(crate) struct PingrespPacket {}
 
 impl Packet for PingrespPacket {
-    fn serialize(&self) -> Vec<u8> {
-        todo!()
+    fn remaining_length(&self) -> usize {
+        0
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) {
+        // Fixed header
+        buf.put_u8(0b1101_0000); // PINGRESP=13
+        buf.put_u8(0); // remaining length
     }
 
     fn deserialize(buf: &[u8]) -> (Self, usize)
     where
         Self: Sized,
     {
-        assert!(buf[0] == 0b1101_0000);
-        assert!(buf[1] == 0);
+        assert!(buf[0] >> 4 == 13);
+        let (remaining_length, i) = extract_remaining_length(buf);
 
-        (Self {}, 2)
+        (Self {}, i + remaining_length)
     }
 }
 
@@ -667,14 +895,14 @@ impl Packet for PingrespPacket {
 pub(crate) struct DisconnectPacket {}
 
 impl Packet for DisconnectPacket {
-    fn serialize(&self) -> Vec<u8> {
-        let mut bytes = vec![];
+    fn remaining_length(&self) -> usize {
+        0
+    }
 
+    fn encode(&self, buf: &mut impl BufMut) {
         // Fixed header
-        bytes.push(0b1110_0000); // DISCONNECT=14
-        bytes.push(0); // remaining length
-
-        bytes
+        buf.put_u8(0b1110_0000); // DISCONNECT=14
+        buf.put_u8(0); // remaining length
     }
 
     fn deserialize(_buf: &[u8]) -> (Self, usize)
@@ -685,25 +913,64 @@ impl Packet for DisconnectPacket {
     }
 }
 
-pub(crate) fn insert_remaining_length(bytes: &mut Vec<u8>) {
-    let mut length = bytes.len() - 1;
-    if length > 268435455 {
+// remaining lengthは4バイトで表せる268435455バイトまで
+pub(crate) const MAX_REMAINING_LENGTH: usize = 268_435_455;
+// 固定ヘッダー (1バイト + remaining length最大4バイト) を含めたパケットの最大サイズ
+pub(crate) const MAX_PACKET_SIZE: usize = 1 + 4 + MAX_REMAINING_LENGTH;
+
+// 固定ヘッダーを含めたパケットのサイズ
+pub(crate) fn packet_size(remaining_length: usize) -> usize {
+    let length_bytes = match remaining_length {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    };
+    1 + length_bytes + remaining_length
+}
+
+pub(crate) fn put_remaining_length(buf: &mut impl BufMut, remaining_length: usize) {
+    if remaining_length > MAX_REMAINING_LENGTH {
         panic!("Too large packet.");
     }
 
-    let mut length_bytes = vec![];
+    // 7ビットずつ下位から書き込み、続きがあれば最上位ビットを立てる
+    let mut length = remaining_length;
     loop {
         let b = (length & 0x7f) as u8;
         length >>= 7;
         if length > 0 {
-            length_bytes.push(b | 0x80);
+            buf.put_u8(b | 0x80);
         } else {
-            length_bytes.push(b);
+            buf.put_u8(b);
             break;
         }
     }
+}
+
+// 文字列の長さは2バイトで書くので、これを超えると切り詰められてしまう
+pub(crate) const MAX_STRING_LENGTH: usize = 65_535;
+
+// パスワードが含まれることもあるので、エラーには長さだけを出す
+pub(crate) fn check_str_length(field: &str, s: &str) -> io::Result<()> {
+    if s.len() > MAX_STRING_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is longer than {} bytes. length={}",
+                field,
+                MAX_STRING_LENGTH,
+                s.len()
+            ),
+        ));
+    }
+    Ok(())
+}
 
-    bytes.splice(1..1, length_bytes);
+// 長さ (2バイト) に続けて文字列を書き込む
+fn put_str(buf: &mut impl BufMut, s: &str) {
+    buf.put_u16(s.len() as u16);
+    buf.put_slice(s.as_bytes());
 }
 
 pub(crate) fn extract_remaining_length(bytes: &[u8]) -> (usize, usize) {
@@ -725,16 +992,42 @@ pub(crate) fn extract_remaining_length(bytes: &[u8]) -> (usize, usize) {
     (length, i + 1)
 }
 
+// バッファの先頭に1パケット分のバイト列が揃っていれば、そのバイト数を返す
+pub(crate) fn complete_packet_length(bytes: &[u8]) -> io::Result<Option<usize>> {
+    Ok(declared_packet_length(bytes)?.filter(|length| bytes.len() >= *length))
+}
+
+// 固定ヘッダーが揃っていれば、本体を受信する前にパケット全体のバイト数を返す
+pub(crate) fn declared_packet_length(bytes: &[u8]) -> io::Result<Option<usize>> {
+    // remaining lengthは最大4バイトで、最後のバイトは最上位ビットが0
+    let Some(length_bytes) = bytes.get(1..) else {
+        return Ok(None);
+    };
+    let Some(position) = length_bytes.iter().take(4).position(|b| b & 0x80 == 0) else {
+        if length_bytes.len() >= 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Protocol violation. reason=Remaining length is longer than 4 bytes",
+            ));
+        }
+        return Ok(None);
+    };
+    let (remaining_length, i) = extract_remaining_length(&bytes[..=position + 1]);
+
+    Ok(Some(i + remaining_length))
+}
+
+// 0はパケットIDとして使えない
 pub(crate) fn generate_packet_id() -> u16 {
     let mut rng = rand::thread_rng();
-    rng.gen()
+    rng.gen_range(1..=u16::MAX)
 }
 
 pub(crate) fn create_replay_packet_with_received_packet(
-    buf: &[u8],
+    buf: &Bytes,
     i: usize,
-) -> (PacketType, Option<PacketType>, usize) {
-    let (packet, size) = PacketType::deserialize(&buf[i..]);
+) -> io::Result<(PacketType, Option<PacketType>, usize)> {
+    let (packet, size) = PacketType::decode(&buf.slice(i..))?;
     let replied_packet = match &packet {
         PacketType::PUBLISH(publish_packet) => {
             if publish_packet.qos == QoS::QoS1 {
@@ -752,11 +1045,28 @@ pub(crate) fn create_replay_packet_with_received_packet(
         PacketType::PUBREL(pubrel_packet) => Some(PacketType::PUBCOMP(PubcompPacket {
             packet_id: pubrel_packet.packet_id,
         })),
+        PacketType::PUBREC(pubrec_packet) => Some(PacketType::PUBREL(PubrelPacket {
+            packet_id: pubrec_packet.packet_id,
+        })),
+        PacketType::PUBACK(_) => None,
+        PacketType::PUBCOMP(_) => None,
+        PacketType::SUBACK(_) => None,
         PacketType::UNSUBACK(_) => None,
         PacketType::PINGRESP(_) => None,
-        _ => unimplemented!(),
+        // CONNACKは接続時にしか届かない
+        _ => return Err(unexpected_packet_type(buf[i] >> 4)),
     };
 
     // (受信したパケット、返信すべきパケット、次に読み出すインデックス)
-    (packet, replied_packet, i + size)
+    Ok((packet, replied_packet, i + size))
+}
+
+fn unexpected_packet_type(packet_type: u8) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Protocol violation. reason=Unexpected packet type from server. packet_type={}",
+            packet_type
+        ),
+    )
 }
@@ -0,0 +1,249 @@
+use log::{debug, error, info, warn};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    client::{self, Client, ConnectOptions},
+    packet,
+    qos::QoS,
+    topic::{TopicFilter, TopicName},
+};
+
+// 転送元からの受信を待つ最大時間 (この間隔で転送先のkeep aliveも確かめる)
+const SOURCE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// 転送先からの受信 (PINGRESPなど) を待つ最大時間
+const DESTINATION_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// "sensors/=site1/sensors/" の形式 (FROM=TO) でトピックの前方一致部分を書き換える
+// 書き換え後もトピック名として使えるように、ワイルドカードとU+0000は使えない
+pub(crate) fn parse_rewrite(rule: &str) -> Result<(String, String), String> {
+    match rule.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !rule.contains(['+', '#', '\0']) => {
+            Ok((from.to_string(), to.to_string()))
+        }
+        _ => Err(format!(
+            "Invalid rewrite rule: '{}'. (FROM=TO, without wildcards)",
+            rule
+        )),
+    }
+}
+
+// 最初に一致したルールだけを適用する
+// 書き換えた結果が空や長すぎる場合はエラー
+pub(crate) fn rewrite_topic(
+    topic: &TopicName,
+    rewrites: &[(String, String)],
+) -> io::Result<TopicName> {
+    for (from, to) in rewrites {
+        if let Some(rest) = topic.as_str().strip_prefix(from.as_str()) {
+            return TopicName::new(format!("{}{}", to, rest));
+        }
+    }
+    Ok(topic.clone())
+}
+
+pub(crate) fn run(
+    mut from: ConnectOptions,
+    to: ConnectOptions,
+    topics: Vec<TopicFilter>,
+    rewrites: Vec<(String, String)>,
+    wait_for_exit: Arc<Mutex<bool>>,
+) {
+    // 元のQoSのまま転送できるように、QoS2で購読する
+    let topic_filters: Vec<(TopicFilter, QoS)> =
+        topics.into_iter().map(|t| (t, QoS::QoS2)).collect();
+    // 転送先に届けてから転送元にPUBACK, PUBRECを返す
+    // 転送の途中で切断や再起動があってもメッセージは失われない (重複して転送されることはある)
+    from.delivery.manual_ack = true;
+
+    let mut source = connect_with_retry(from, &wait_for_exit);
+    let mut destination = connect_with_retry(to, &wait_for_exit);
+    let (Some(source), Some(destination)) = (source.as_mut(), destination.as_mut()) else {
+        return;
+    };
+    let _ = source.set_poll_interval(Some(SOURCE_POLL_INTERVAL));
+    let _ = destination.set_poll_interval(Some(DESTINATION_POLL_INTERVAL));
+    subscribe_with_retry(source, &topic_filters, &wait_for_exit);
+
+    let mut deadline = None;
+    loop {
+        if *wait_for_exit.lock().unwrap() {
+            // 転送元とのQoS2のやり取り (PUBREL待ち) を終えてから切断する
+            let deadline =
+                *deadline.get_or_insert_with(|| Instant::now() + client::SHUTDOWN_TIMEOUT);
+            if !source.has_in_flight() {
+                break;
+            }
+            if Instant::now() >= deadline {
+                warn!("Shutdown timed out. Disconnect without waiting for PUBREL.");
+                break;
+            }
+        }
+
+        // 転送するメッセージがなくても、転送先との接続をkeep aliveで保つ
+        if let Err(e) = destination.receive() {
+            warn!("Lost connection to destination broker. error={}", e);
+            reconnect_with_retry(destination, &wait_for_exit);
+        }
+
+        let received_packet = match source.poll() {
+            Ok(Some(received_packet)) => received_packet,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Lost connection to source broker. error={}", e);
+                if deadline.is_some() {
+                    break;
+                }
+                reconnect_with_retry(source, &wait_for_exit);
+                subscribe_with_retry(source, &topic_filters, &wait_for_exit);
+                continue;
+            }
+        };
+
+        relay_message(
+            source,
+            destination,
+            &received_packet,
+            &rewrites,
+            &wait_for_exit,
+        );
+    }
+
+    let _ = source.disconnect();
+    let _ = destination.disconnect();
+}
+
+// 受信したメッセージを転送先にpublishし、届いたら (または捨てたら) 転送元にACKを返す
+// 転送先で再送を諦めたメッセージにはACKを返さず、転送元のブローカーに再送させる
+pub(crate) fn relay_message(
+    source: &mut Client,
+    destination: &mut Client,
+    received_packet: &packet::PublishPacket,
+    rewrites: &[(String, String)],
+    wait_for_exit: &Mutex<bool>,
+) {
+    let topic_name = match rewrite_topic(&received_packet.topic_name, rewrites) {
+        Ok(topic_name) => topic_name,
+        Err(e) => {
+            warn!(
+                "Drop message from topic={}. error={}",
+                received_packet.topic_name, e
+            );
+            ack(source, received_packet);
+            return;
+        }
+    };
+    debug!(
+        "Relay message from topic={} to topic={}",
+        received_packet.topic_name, topic_name
+    );
+    let publish_packet = packet::PublishPacket::new(
+        false,
+        received_packet.qos,
+        received_packet.retain,
+        topic_name,
+        None,
+        received_packet.payload.clone(),
+    );
+    // 転送先のmax_packet_sizeを超えるメッセージは、何度送っても届かないので捨てる
+    if let Err(e) = destination.check_packet_size(&publish_packet) {
+        error!(
+            "Drop message from topic={}. error={}",
+            received_packet.topic_name, e
+        );
+        ack(source, received_packet);
+        return;
+    }
+
+    let packet_id = publish_packet.packet_id;
+    let mut result = destination.publish(publish_packet);
+    while let Err(e) = &result {
+        // 再送を諦めたメッセージは、転送先に接続し直しても再送されない
+        if packet_id.is_some_and(|packet_id| !destination.is_in_flight(packet_id)) {
+            break;
+        }
+        warn!("Failed to publish to destination broker. error={}", e);
+        if !reconnect_with_retry(destination, wait_for_exit) {
+            break;
+        }
+        // ACKが届いていなければ再接続時に再送されているので、ACKを待つだけでよい
+        result = destination.wait_for_ack(packet_id);
+    }
+    match result {
+        Ok(()) => ack(source, received_packet),
+        Err(e) => error!(
+            "Failed to relay message from topic={}. Leave it unacknowledged on source broker. error={}",
+            received_packet.topic_name, e
+        ),
+    }
+}
+
+// 失敗した場合は、再接続後にブローカーから再送されるので警告だけ出す
+fn ack(source: &mut Client, publish_packet: &packet::PublishPacket) {
+    if let Err(e) = source.ack(publish_packet) {
+        warn!(
+            "Failed to acknowledge source broker. packet_id={:?}, error={}",
+            publish_packet.packet_id, e
+        );
+    }
+}
+
+fn connect_with_retry(options: ConnectOptions, wait_for_exit: &Mutex<bool>) -> Option<Client> {
+    let mut backoff = client::MIN_BACKOFF;
+    while !*wait_for_exit.lock().unwrap() {
+        match Client::connect(options.clone()) {
+            Ok(client) => return Some(client),
+            Err(e) => {
+                warn!(
+                    "Failed to connect broker={}. Retry after {:?}. error={}",
+                    options.broker, backoff, e
+                );
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(client::MAX_BACKOFF);
+            }
+        }
+    }
+    None
+}
+
+fn reconnect_with_retry(client: &mut Client, wait_for_exit: &Mutex<bool>) -> bool {
+    let mut backoff = client::MIN_BACKOFF;
+    while !*wait_for_exit.lock().unwrap() {
+        match client.reconnect() {
+            Ok(()) => return true,
+            Err(e) => {
+                warn!(
+                    "Failed to reconnect. Retry after {:?}. error={}",
+                    backoff, e
+                );
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(client::MAX_BACKOFF);
+            }
+        }
+    }
+    false
+}
+
+fn subscribe_with_retry(
+    client: &mut Client,
+    topic_filters: &[(TopicFilter, QoS)],
+    wait_for_exit: &Mutex<bool>,
+) {
+    while !*wait_for_exit.lock().unwrap() {
+        match client.subscribe(topic_filters.to_vec()) {
+            Ok(granted) => {
+                info!("Relay topics={:?}", granted);
+                // 許可されたQoSが低ければ、転送先にもそのQoSで届く
+                let _ = client::check_granted_qos(topic_filters, &granted, false);
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to subscribe. error={}", e);
+                reconnect_with_retry(client, wait_for_exit);
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+// トピック名 (PUBLISHの宛先) とトピックフィルター (SUBSCRIBEの対象)
+// 作るときにMQTT 3.1.1の規則を確かめるので、パケットにするときは長さなどを確かめなくてよい
+use bytes::Bytes;
+use bytestring::ByteString;
+use std::{fmt, io};
+
+// 長さは2バイトで表すので、UTF-8で65535バイトまで
+const MAX_LENGTH: usize = u16::MAX as usize;
+
+// 受信したPUBLISHのトピック名はコピーせずに、受信したバイト列を共有する
+// ByteStringはUTF-8であることを確かめてからBytesを包むので、strとしてそのまま読める
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) struct TopicName(ByteString);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct TopicFilter(String);
+
+impl TopicName {
+    // ワイルドカード (+, #) は使えない
+    pub(crate) fn new(name: impl Into<String>) -> io::Result<Self> {
+        let name = name.into();
+        check_name(&name)?;
+        Ok(Self(ByteString::from(name)))
+    }
+
+    pub(crate) fn from_bytes(name: Bytes) -> io::Result<Self> {
+        let name = ByteString::try_from(name).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid topic: not UTF-8. ({})", e),
+            )
+        })?;
+        check_name(&name)?;
+        Ok(Self(name))
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+// TopicNameを作らずにトピック名として使えるかを確かめる (受信したパケットの検査用)
+pub(crate) fn check_name(name: &str) -> io::Result<()> {
+    check_common(name)?;
+    if name.contains(['+', '#']) {
+        return Err(invalid(name, "wildcards are not allowed in a topic name"));
+    }
+    Ok(())
+}
+
+impl TopicFilter {
+    // + は1階層全体、# は最後の階層全体でなければならない (例: "a/+/c", "a/#")
+    pub(crate) fn new(filter: impl Into<String>) -> io::Result<Self> {
+        let filter = filter.into();
+        check_common(&filter)?;
+        let levels: Vec<&str> = filter.split('/').collect();
+        for (i, level) in levels.iter().enumerate() {
+            if level.contains('+') && *level != "+" {
+                return Err(invalid(&filter, "'+' must occupy an entire level"));
+            }
+            if level.contains('#') && (*level != "#" || i != levels.len() - 1) {
+                return Err(invalid(&filter, "'#' must be the last level by itself"));
+            }
+        }
+        Ok(Self(filter))
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+fn check_common(s: &str) -> io::Result<()> {
+    if s.is_empty() {
+        return Err(invalid(s, "empty topic"));
+    }
+    if s.len() > MAX_LENGTH {
+        return Err(invalid(s, "longer than 65535 bytes"));
+    }
+    if s.contains('\0') {
+        return Err(invalid(s, "U+0000 is not allowed"));
+    }
+    Ok(())
+}
+
+fn invalid(s: &str, reason: &str) -> io::Error {
+    // 長すぎるトピックをそのままエラーメッセージに入れないように、先頭だけ表示する
+    let shown: String = s.chars().take(64).collect();
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid topic: '{}'. ({})", shown, reason),
+    )
+}
+
+impl fmt::Display for TopicName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for TopicName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TopicName").field(&self.as_str()).finish()
+    }
+}
+
+impl fmt::Display for TopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// コマンドライン引数用
+pub(crate) fn parse_topic_name(name: &str) -> Result<TopicName, String> {
+    TopicName::new(name).map_err(|e| e.to_string())
+}
+
+pub(crate) fn parse_topic_filter(filter: &str) -> Result<TopicFilter, String> {
+    TopicFilter::new(filter).map_err(|e| e.to_string())
+}
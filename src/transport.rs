@@ -0,0 +1,381 @@
+use bytes::Bytes;
+use core::time;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::{
+    io::{self, IoSlice, Read, Write},
+    net::{Shutdown, TcpStream},
+};
+
+// ブローカーとの通信路
+// プロトコルの処理 (client.rs) はこのトレイトだけに依存するので、TCP以外の通信路も差し替えられる
+pub(crate) trait Transport: Read + Write + Send {
+    // Noneの場合は受信できるまでブロックする
+    // タイムアウトした場合、readはWouldBlockまたはTimedOutを返す
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()>;
+
+    // 小さいパケットをまとめずにすぐ送る (TCP_NODELAY)
+    // TCP以外の通信路では何もしない
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+// 複数のバイト列を、できるだけ少ない回数の書き込み (writev) で送る
+// 一部しか書き込めなかった場合は、続きから書き込み直す
+pub(crate) fn write_all_vectored<W: Write + ?Sized>(
+    writer: &mut W,
+    segments: &[Bytes],
+) -> io::Result<()> {
+    let mut slices: Vec<IoSlice> = segments.iter().map(|bytes| IoSlice::new(bytes)).collect();
+    let mut slices = &mut slices[..];
+    // 先頭の空のバイト列を取り除く
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(size) => IoSlice::advance_slices(&mut slices, size),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// 接続先のアドレスからTransportを作る関数 (再接続時にも呼ばれる)
+pub(crate) type Connector = Box<dyn FnMut(&str) -> io::Result<Box<dyn Transport>> + Send>;
+
+// "unix:///run/mosquitto.sock" の形式であればUnixドメインソケット、それ以外はTCP (HOST:PORT)
+pub(crate) fn connect(address: &str) -> io::Result<Box<dyn Transport>> {
+    if let Some(path) = address.strip_prefix("unix://") {
+        return connect_unix(path);
+    }
+    Ok(Box::new(TcpStream::connect(address)?))
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> io::Result<Box<dyn Transport>> {
+    Ok(Box::new(UnixStream::connect(path)?))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &str) -> io::Result<Box<dyn Transport>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain socket is not supported on this platform",
+    ))
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+// テスト用のメモリ上の通信路
+// pair()で作った2つの端点は、一方に書き込んだバイト列をもう一方から読み出せる
+#[cfg(test)]
+pub(crate) mod memory {
+    use core::time;
+    use std::{
+        collections::VecDeque,
+        io::{self, Read, Write},
+        sync::{Arc, Condvar, Mutex},
+    };
+
+    use super::Transport;
+
+    #[derive(Default)]
+    struct Pipe {
+        state: Mutex<(VecDeque<u8>, bool)>, // (未読のバイト列, クローズ済み)
+        condvar: Condvar,
+    }
+
+    impl Pipe {
+        fn close(&self) {
+            self.state.lock().unwrap().1 = true;
+            self.condvar.notify_all();
+        }
+    }
+
+    struct Endpoint {
+        incoming: Arc<Pipe>,
+        outgoing: Arc<Pipe>,
+        read_timeout: Mutex<Option<time::Duration>>,
+    }
+
+    // 端点のハンドルがすべて破棄されたら、相手側にはEOFが見える
+    impl Drop for Endpoint {
+        fn drop(&mut self) {
+            self.incoming.close();
+            self.outgoing.close();
+        }
+    }
+
+    pub(crate) struct MemoryTransport {
+        endpoint: Arc<Endpoint>,
+    }
+
+    pub(crate) fn pair() -> (MemoryTransport, MemoryTransport) {
+        let a_to_b = Arc::new(Pipe::default());
+        let b_to_a = Arc::new(Pipe::default());
+        let a = Endpoint {
+            incoming: b_to_a.clone(),
+            outgoing: a_to_b.clone(),
+            read_timeout: Mutex::new(None),
+        };
+        let b = Endpoint {
+            incoming: a_to_b,
+            outgoing: b_to_a,
+            read_timeout: Mutex::new(None),
+        };
+
+        (
+            MemoryTransport {
+                endpoint: Arc::new(a),
+            },
+            MemoryTransport {
+                endpoint: Arc::new(b),
+            },
+        )
+    }
+
+    impl Read for MemoryTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let timeout = *self.endpoint.read_timeout.lock().unwrap();
+            let pipe = &self.endpoint.incoming;
+            let mut state = pipe.state.lock().unwrap();
+
+            while state.0.is_empty() && !state.1 {
+                state = match timeout {
+                    Some(timeout) => {
+                        let (state, result) = pipe.condvar.wait_timeout(state, timeout).unwrap();
+                        if result.timed_out() && state.0.is_empty() {
+                            return Err(io::ErrorKind::WouldBlock.into());
+                        }
+                        state
+                    }
+                    None => pipe.condvar.wait(state).unwrap(),
+                };
+            }
+
+            let size = buf.len().min(state.0.len());
+            for (b, v) in buf.iter_mut().zip(state.0.drain(..size)) {
+                *b = v;
+            }
+            Ok(size)
+        }
+    }
+
+    impl Write for MemoryTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let pipe = &self.endpoint.outgoing;
+            let mut state = pipe.state.lock().unwrap();
+            if state.1 {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            state.0.extend(buf);
+            pipe.condvar.notify_all();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+            *self.endpoint.read_timeout.lock().unwrap() = timeout;
+            Ok(())
+        }
+
+        fn shutdown(&self) -> io::Result<()> {
+            self.endpoint.incoming.close();
+            self.endpoint.outgoing.close();
+            Ok(())
+        }
+    }
+}
+
+// テスト用の障害を注入する通信路
+// シードを固定した乱数で、書き込みの分割・受信の結合・遅延・バイト化けを起こし、指定したバイト数で接続を切る
+#[cfg(test)]
+pub(crate) mod faulty {
+    use core::time;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::{
+        cell::Cell,
+        io::{self, Read, Write},
+    };
+
+    use super::{Connector, Transport};
+
+    #[derive(Clone, Debug, Default)]
+    pub(crate) struct Faults {
+        // 書き込みを1バイトずつに分割する確率
+        pub(crate) split_writes: f64,
+        // 受信を待ってから、届いたバイト列をまとめて返す確率
+        pub(crate) coalesce_reads: f64,
+        // 読み書きの前に遅延させる確率と時間
+        pub(crate) delay: f64,
+        pub(crate) delay_duration: time::Duration,
+        // 受信したバイトを1ビット反転させる確率 (バイトごと)
+        pub(crate) corrupt: f64,
+        // 書き込んだバイト数がこれに達したら接続を切る (パケットの途中でも切る)
+        pub(crate) drop_after_written: Option<usize>,
+    }
+
+    // 受信をまとめる間に待つ時間
+    const COALESCE_WINDOW: time::Duration = time::Duration::from_millis(20);
+
+    pub(crate) struct FaultyTransport {
+        inner: Box<dyn Transport>,
+        faults: Faults,
+        rng: StdRng,
+        read_timeout: Cell<Option<time::Duration>>,
+        pending: Vec<u8>,
+        written: usize,
+    }
+
+    impl FaultyTransport {
+        pub(crate) fn new(inner: Box<dyn Transport>, faults: Faults, seed: u64) -> Self {
+            Self {
+                inner,
+                faults,
+                rng: StdRng::seed_from_u64(seed),
+                read_timeout: Cell::new(None),
+                pending: vec![],
+                written: 0,
+            }
+        }
+
+        fn maybe_delay(&mut self) {
+            if self.rng.gen_bool(self.faults.delay) {
+                std::thread::sleep(self.faults.delay_duration);
+            }
+        }
+
+        fn coalesce(&mut self) -> io::Result<()> {
+            self.inner.set_read_timeout(Some(COALESCE_WINDOW))?;
+            let mut buffer = [0; 1024];
+            let result = loop {
+                match self.inner.read(&mut buffer) {
+                    Ok(0) => break Ok(()),
+                    Ok(size) => self.pending.extend(&buffer[..size]),
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        break Ok(())
+                    }
+                    Err(e) => break Err(e),
+                }
+            };
+            self.inner.set_read_timeout(self.read_timeout.get())?;
+            result
+        }
+    }
+
+    impl Read for FaultyTransport {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.maybe_delay();
+
+            if self.pending.is_empty() {
+                let mut buffer = [0; 1024];
+                let size = self.inner.read(&mut buffer)?;
+                self.pending.extend(&buffer[..size]);
+                if size > 0 && self.rng.gen_bool(self.faults.coalesce_reads) {
+                    self.coalesce()?;
+                }
+            }
+
+            let size = buf.len().min(self.pending.len());
+            for (b, v) in buf.iter_mut().zip(self.pending.drain(..size)) {
+                *b = if self.rng.gen_bool(self.faults.corrupt) {
+                    v ^ (1 << self.rng.gen_range(0..8))
+                } else {
+                    v
+                };
+            }
+            Ok(size)
+        }
+    }
+
+    impl Write for FaultyTransport {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.maybe_delay();
+
+            let mut size = if self.rng.gen_bool(self.faults.split_writes) {
+                buf.len().min(1)
+            } else {
+                buf.len()
+            };
+
+            if let Some(limit) = self.faults.drop_after_written {
+                if self.written + size >= limit {
+                    size = limit - self.written;
+                    self.inner.write_all(&buf[..size])?;
+                    self.written += size;
+                    self.inner.shutdown()?;
+                    return Err(io::ErrorKind::ConnectionReset.into());
+                }
+            }
+
+            let size = self.inner.write(&buf[..size])?;
+            self.written += size;
+            Ok(size)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl Transport for FaultyTransport {
+        fn set_read_timeout(&self, timeout: Option<time::Duration>) -> io::Result<()> {
+            // coalesce後に元に戻すために保持しておく
+            self.read_timeout.set(timeout);
+            self.inner.set_read_timeout(timeout)
+        }
+
+        fn shutdown(&self) -> io::Result<()> {
+            self.inner.shutdown()
+        }
+    }
+
+    // n回目の接続にschedule[n]の障害を注入する (scheduleを使い切った後は障害なし)
+    pub(crate) fn connector(mut inner: Connector, schedule: Vec<Faults>, seed: u64) -> Connector {
+        let mut connections = 0;
+        Box::new(move |address| {
+            let faults = schedule.get(connections).cloned().unwrap_or_default();
+            let transport =
+                FaultyTransport::new(inner(address)?, faults, seed + connections as u64);
+            connections += 1;
+            Ok(Box::new(transport))
+        })
+    }
+}